@@ -0,0 +1,143 @@
+// typed attribute values
+// documents are kept as their original JSON. their attributes are also
+// stored one row per value in the attributes side table, as SQLite integers,
+// reals or text next to their JSON type, so they can be compared and sorted
+// by type. array elements get a row each, typed "array".
+// nested attributes are flattened to their dotted path, author.name, and
+// every setting, filter and facet refers to them by that path
+use json::JsonValue;
+
+// joins the elements of an array in its FTS5 text. unicode61 treats it as a
+// separator, highlights are split on it to get one result per element
+pub const ARRAY_SEPARATOR: char = '\u{1f}';
+
+// an attribute path as written in settings and filters. `[]` marks an array
+// and can be left out: tags[] is tags, authors[].name is authors.name
+pub fn path(name: &str) -> String {
+    name.replace("[]", "")
+}
+
+// whether an attribute named in a setting covers an attribute path: author
+// covers author.name
+pub fn covers(name: &str, attribute: &str) -> bool {
+    let name = path(name);
+    attribute == name
+        || attribute
+            .strip_prefix(name.as_str())
+            .is_some_and(|rest| rest.starts_with('.'))
+}
+
+// the attributes of a document by path, in document order. nested objects
+// are walked, arrays of objects give an array of the values of each member
+// path. every value is a scalar, null, or an array of scalars. values
+// sharing a path end up in one array
+pub fn flatten(doc: &JsonValue) -> Vec<(String, JsonValue)> {
+    let mut attributes = vec![];
+    for (key, value) in doc.entries() {
+        collect(key.to_string(), value, false, &mut attributes);
+    }
+    attributes
+}
+
+fn collect(path: String, value: &JsonValue, in_array: bool, out: &mut Vec<(String, JsonValue)>) {
+    match value {
+        JsonValue::Object(_) => {
+            for (key, member) in value.entries() {
+                collect(format!("{}.{}", path, key), member, in_array, out);
+            }
+        }
+        JsonValue::Array(members) => {
+            // empty arrays still name an attribute
+            if members.is_empty() && !out.iter().any(|(p, _)| *p == path) {
+                out.push((path.clone(), JsonValue::new_array()));
+            }
+            for member in members {
+                collect(path.clone(), member, true, out);
+            }
+        }
+        _ => match out.iter_mut().find(|(p, _)| *p == path) {
+            // a dotted key, "a.b", and a nested one, {"a": {"b": ...}}, share
+            // their path: their values are merged into one array
+            Some((_, JsonValue::Array(members))) => members.push(value.clone()),
+            Some((_, values)) => *values = JsonValue::from(vec![values.take(), value.clone()]),
+            None if in_array => out.push((path, JsonValue::from(vec![value.clone()]))),
+            None => out.push((path, value.clone())),
+        },
+    }
+}
+
+// the text FTS5 indexes for an attribute: strings raw, other values as their
+// JSON text, array elements joined by ARRAY_SEPARATOR. None for null
+pub fn text(value: &JsonValue) -> Option<String> {
+    match value {
+        JsonValue::Null => None,
+        JsonValue::Array(members) => Some(
+            members
+                .iter()
+                .filter(|member| !member.is_null())
+                .map(|member| member.to_string())
+                .collect::<Vec<String>>()
+                .join(&ARRAY_SEPARATOR.to_string()),
+        ),
+        _ => Some(value.to_string()),
+    }
+}
+
+pub fn typed_values(value: &JsonValue) -> Vec<(&'static str, sqlite::Value)> {
+    match value {
+        JsonValue::Array(members) => members
+            .iter()
+            .filter_map(scalar)
+            .map(|(_, value)| ("array", value))
+            .collect(),
+        _ => scalar(value).into_iter().collect(),
+    }
+}
+
+// booleans are stored as 0/1, nested arrays and objects as their JSON text
+fn scalar(value: &JsonValue) -> Option<(&'static str, sqlite::Value)> {
+    match value {
+        JsonValue::Null => None,
+        JsonValue::Boolean(b) => Some(("bool", sqlite::Value::Integer(*b as i64))),
+        JsonValue::Number(_) => {
+            let number = value.as_f64()?;
+            match value.as_i64() {
+                Some(integer) if number.fract() == 0.0 => {
+                    Some(("integer", sqlite::Value::Integer(integer)))
+                }
+                _ => Some(("real", sqlite::Value::Float(number))),
+            }
+        }
+        JsonValue::Array(_) => Some(("array", sqlite::Value::String(value.dump()))),
+        JsonValue::Object(_) => Some(("object", sqlite::Value::String(value.dump()))),
+        _ => Some(("text", sqlite::Value::String(value.to_string()))),
+    }
+}
+
+// sets a value at an attribute path, creating the objects along it
+pub fn set_path(doc: &mut JsonValue, path: &str, value: JsonValue) {
+    match path.split_once('.') {
+        Some((key, rest)) => set_path(&mut doc[key], rest, value),
+        None => doc[path] = value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use json::object;
+
+    #[test]
+    fn dotted_and_nested_keys_merge_their_values() {
+        let doc = object! { "a.b": "dotted", a: { b: "nested" } };
+        assert_eq!(
+            flatten(&doc),
+            vec![("a.b".to_string(), json::array!["dotted", "nested"])]
+        );
+        let doc = object! { a: { b: ["x", "y"] }, "a.b": "z" };
+        assert_eq!(
+            flatten(&doc),
+            vec![("a.b".to_string(), json::array!["x", "y", "z"])]
+        );
+    }
+}
@@ -0,0 +1,236 @@
+// Algolia batch protocol
+// a batch payload is {"requests": [{"action": "...", "body": {...}}, ...]}
+// and every request in it is applied in a single transaction
+use crate::index_engine::IndexError;
+use json::object;
+use json::JsonValue;
+use uuid::Uuid;
+
+pub enum BatchAction {
+    // add a document, generating an objectID when none is given
+    AddObject(JsonValue),
+    // replace a document, objectID required
+    UpdateObject(JsonValue),
+    // merge attributes into a document, optionally creating it
+    PartialUpdateObject {
+        body: JsonValue,
+        create_if_not_exists: bool,
+    },
+    DeleteObject(String),
+    // drop documents and schema
+    Delete,
+    // drop documents, keep schema and settings
+    Clear,
+}
+
+impl BatchAction {
+    pub fn from_json(request: &JsonValue) -> Result<BatchAction, IndexError> {
+        let body = &request["body"];
+        match request["action"].as_str() {
+            Some("addObject") => Ok(BatchAction::AddObject(object_body(body)?)),
+            Some("updateObject") => {
+                required_object_id(body)?;
+                Ok(BatchAction::UpdateObject(object_body(body)?))
+            }
+            Some("partialUpdateObject") => {
+                required_object_id(body)?;
+                Ok(BatchAction::PartialUpdateObject {
+                    body: object_body(body)?,
+                    create_if_not_exists: true,
+                })
+            }
+            Some("partialUpdateObjectNoCreate") => {
+                required_object_id(body)?;
+                Ok(BatchAction::PartialUpdateObject {
+                    body: object_body(body)?,
+                    create_if_not_exists: false,
+                })
+            }
+            Some("deleteObject") => Ok(BatchAction::DeleteObject(required_object_id(body)?)),
+            Some("delete") => Ok(BatchAction::Delete),
+            Some("clear") => Ok(BatchAction::Clear),
+            Some(action) => Err(IndexError::InvalidDocument(format!(
+                "unknown batch action {}",
+                action
+            ))),
+            None => Err(IndexError::InvalidDocument(
+                "batch request without action".to_string(),
+            )),
+        }
+    }
+
+    // the request this action was parsed from, as stored in the task queue
+    pub fn to_json(&self) -> JsonValue {
+        let (action, body) = match self {
+            BatchAction::AddObject(doc) => ("addObject", doc.clone()),
+            BatchAction::UpdateObject(doc) => ("updateObject", doc.clone()),
+            BatchAction::PartialUpdateObject {
+                body,
+                create_if_not_exists: true,
+            } => ("partialUpdateObject", body.clone()),
+            BatchAction::PartialUpdateObject { body, .. } => {
+                ("partialUpdateObjectNoCreate", body.clone())
+            }
+            BatchAction::DeleteObject(object_id) => {
+                ("deleteObject", object! { objectID: object_id.clone() })
+            }
+            BatchAction::Delete => ("delete", JsonValue::new_object()),
+            BatchAction::Clear => ("clear", JsonValue::new_object()),
+        };
+        object! { action: action, body: body }
+    }
+
+    // the objectID the action touches. documents added without one get it
+    // here, before the action is queued
+    pub fn assign_object_id(&mut self) -> Option<String> {
+        match self {
+            BatchAction::AddObject(doc) => {
+                if doc["objectID"].is_null() {
+                    doc["objectID"] = Uuid::new_v4().to_string().into();
+                }
+                Some(doc["objectID"].to_string())
+            }
+            BatchAction::UpdateObject(doc) | BatchAction::PartialUpdateObject { body: doc, .. } => {
+                Some(doc["objectID"].to_string())
+            }
+            BatchAction::DeleteObject(object_id) => Some(object_id.clone()),
+            BatchAction::Delete | BatchAction::Clear => None,
+        }
+    }
+
+    // parses every request of a batch payload, reporting the first bad one
+    pub fn from_requests(requests: &JsonValue) -> Result<Vec<BatchAction>, IndexError> {
+        if !requests.is_array() {
+            return Err(IndexError::InvalidDocument(
+                "requests must be an array".to_string(),
+            ));
+        }
+        requests
+            .members()
+            .enumerate()
+            .map(|(position, request)| {
+                BatchAction::from_json(request).map_err(|e| IndexError::Batch {
+                    position,
+                    error: Box::new(e),
+                })
+            })
+            .collect()
+    }
+}
+
+fn object_body(body: &JsonValue) -> Result<JsonValue, IndexError> {
+    if body.is_object() {
+        Ok(body.clone())
+    } else {
+        Err(IndexError::InvalidDocument(
+            "document must be a JSON object".to_string(),
+        ))
+    }
+}
+
+fn required_object_id(body: &JsonValue) -> Result<String, IndexError> {
+    let object_id = &body["objectID"];
+    if (object_id.is_string() || object_id.is_number()) && !object_id.to_string().is_empty() {
+        Ok(object_id.to_string())
+    } else {
+        Err(IndexError::InvalidDocument(
+            "objectID is required".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(requests: JsonValue) -> String {
+        match BatchAction::from_requests(&requests) {
+            Ok(_) => panic!("{} was accepted", requests),
+            Err(e) => {
+                assert_eq!(e.status(), 400);
+                e.to_string()
+            }
+        }
+    }
+
+    #[test]
+    fn every_action_is_parsed() {
+        let actions = BatchAction::from_requests(&json::array![
+            { action: "addObject", body: { name: "a" } },
+            { action: "updateObject", body: { objectID: "1", name: "b" } },
+            { action: "partialUpdateObject", body: { objectID: 2, name: "c" } },
+            { action: "partialUpdateObjectNoCreate", body: { objectID: "3" } },
+            { action: "deleteObject", body: { objectID: "4" } },
+            { action: "delete" },
+            { action: "clear", body: {} },
+        ])
+        .unwrap();
+        let requests: Vec<JsonValue> = actions.iter().map(|action| action.to_json()).collect();
+        let kinds: Vec<&str> = requests
+            .iter()
+            .map(|request| request["action"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "addObject",
+                "updateObject",
+                "partialUpdateObject",
+                "partialUpdateObjectNoCreate",
+                "deleteObject",
+                "delete",
+                "clear"
+            ]
+        );
+        assert_eq!(requests[2]["body"]["objectID"], 2);
+    }
+
+    #[test]
+    fn unknown_actions_are_refused() {
+        assert!(error(json::array![{ action: "upsert", body: {} }])
+            .contains("unknown batch action upsert"));
+        assert!(error(json::array![{ body: { name: "a" } }]).contains("without action"));
+        // the position of the bad request is reported
+        assert!(error(json::array![
+            { action: "clear" },
+            { action: "bogus" },
+        ])
+        .starts_with("requests[1]"));
+    }
+
+    #[test]
+    fn actions_on_records_need_a_body() {
+        for action in [
+            "addObject",
+            "updateObject",
+            "partialUpdateObject",
+            "partialUpdateObjectNoCreate",
+            "deleteObject",
+        ] {
+            error(json::array![{ action: action }]);
+            error(json::array![{ action: action, body: [] }]);
+        }
+        error(json::array![{ action: "updateObject", body: { name: "a" } }]);
+        error(json::array![{ action: "deleteObject", body: { objectID: "" } }]);
+    }
+
+    #[test]
+    fn requests_must_be_an_array() {
+        assert!(error(JsonValue::Null).contains("requests must be an array"));
+        error(object! { action: "clear" });
+        assert!(BatchAction::from_requests(&json::array![])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn added_records_get_an_object_id() {
+        let mut action = BatchAction::AddObject(object! { name: "a" });
+        let object_id = action.assign_object_id().unwrap();
+        assert!(!object_id.is_empty());
+        assert_eq!(action.to_json()["body"]["objectID"], object_id.as_str());
+        let mut action = BatchAction::AddObject(object! { objectID: 7 });
+        assert_eq!(action.assign_object_id(), Some("7".to_string()));
+        assert_eq!(BatchAction::Clear.assign_object_id(), None);
+    }
+}
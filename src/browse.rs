@@ -0,0 +1,138 @@
+// browsing every document of an index
+// documents come in rowid order, one batch per request. the cursor of a
+// batch holds the search parameters and the last rowid returned: the next
+// batch carries on after that rowid with the same parameters, so documents
+// added or updated meanwhile don't shift the ones still to come. nbHits is
+// only counted for the first batch
+use crate::index_engine::IndexError;
+use crate::search_params::SearchParams;
+use json::object;
+use json::JsonValue;
+
+pub const BROWSE_HITS_PER_PAGE: u64 = 1000;
+
+pub struct Cursor {
+    // the search parameters, url encoded
+    pub params: String,
+    pub after: i64,
+}
+
+impl Cursor {
+    // hex encoded JSON, clients only send it back
+    pub fn encode(&self) -> String {
+        object! { params: self.params.clone(), after: self.after }
+            .dump()
+            .bytes()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    pub fn decode(cursor: &str) -> Result<Cursor, IndexError> {
+        let invalid = || IndexError::InvalidParameter(format!("Invalid cursor: {}", cursor));
+        if !cursor.len().is_multiple_of(2) || !cursor.is_ascii() {
+            return Err(invalid());
+        }
+        let bytes = (0..cursor.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&cursor[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?;
+        let decoded = String::from_utf8(bytes)
+            .ok()
+            .and_then(|text| json::parse(&text).ok())
+            .ok_or_else(invalid)?;
+        match (decoded["params"].as_str(), decoded["after"].as_i64()) {
+            (Some(params), Some(after)) => Ok(Cursor {
+                params: params.to_string(),
+                after,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+// the parameters of a browse request and the rowid to start after. a
+// request with a cursor takes its parameters from the cursor only
+pub fn from_request(request: &JsonValue) -> Result<(SearchParams, i64), IndexError> {
+    if let Some(cursor) = request["cursor"].as_str() {
+        let cursor = Cursor::decode(cursor)?;
+        let params = SearchParams::from_request(&object! { params: cursor.params })?;
+        return Ok((params, cursor.after));
+    }
+
+    let mut params = SearchParams::from_request(request)?;
+    if !params.is_set("hitsPerPage") {
+        params.set("hitsPerPage", &BROWSE_HITS_PER_PAGE.into())?;
+    }
+    Ok((params, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> String {
+        text.bytes().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let cursor = Cursor {
+            params: "query=caf%C3%A9&filters=price+%3C+10".to_string(),
+            after: 41,
+        };
+        let encoded = cursor.encode();
+        assert!(encoded.chars().all(|c| c.is_ascii_hexdigit()));
+        let decoded = Cursor::decode(&encoded).unwrap();
+        assert_eq!(decoded.params, cursor.params);
+        assert_eq!(decoded.after, 41);
+    }
+
+    #[test]
+    fn requests_with_a_cursor_take_its_parameters() {
+        let (params, after) = from_request(&object! { query: "ignored", hitsPerPage: 10 }).unwrap();
+        assert_eq!(after, 0);
+        let cursor = Cursor {
+            params: params.to_query_string(),
+            after: 10,
+        };
+        let (params, after) =
+            from_request(&object! { cursor: cursor.encode(), query: "other" }).unwrap();
+        assert_eq!(params.query, "ignored");
+        assert_eq!(params.hits_per_page, 10);
+        assert_eq!(after, 10);
+    }
+
+    #[test]
+    fn browsing_defaults_to_large_batches() {
+        let (params, _) = from_request(&object! {}).unwrap();
+        assert_eq!(params.hits_per_page, BROWSE_HITS_PER_PAGE);
+    }
+
+    #[test]
+    fn malformed_cursors_are_refused() {
+        for cursor in [
+            String::new(),
+            "abc".to_string(),
+            "zz".to_string(),
+            "é1".to_string(),
+            hex("not json"),
+            hex("\u{0}\u{ff}"),
+            "ff".to_string(),
+            hex(r#"{"params": "query="}"#),
+            hex(r#"{"after": 3}"#),
+            hex(r#"{"params": 3, "after": 3}"#),
+            hex(r#"{"params": "query=", "after": "3"}"#),
+        ] {
+            let error = Cursor::decode(&cursor).err().unwrap();
+            assert_eq!(error.status(), 400, "{}", cursor);
+        }
+        assert!(from_request(&object! { cursor: "zz" }).is_err());
+        // a valid cursor with bad parameters
+        let cursor = Cursor {
+            params: "bogus=1".to_string(),
+            after: 0,
+        };
+        assert!(from_request(&object! { cursor: cursor.encode() }).is_err());
+    }
+}
@@ -0,0 +1,20 @@
+// facet values of documents
+// every attribute listed in attributesForFaceting (and _tags) gets one row
+// per value in the facets side table, next to the document rowid. arrays give
+// one row per element, numbers keep their numeric value for facets_stats
+use json::JsonValue;
+
+pub const DEFAULT_MAX_VALUES_PER_FACET: u64 = 100;
+pub const MAX_VALUES_PER_FACET: u64 = 1000;
+
+// the facet values of an attribute. anything that isn't a string is
+// counted by its JSON text
+pub fn values(value: &JsonValue) -> Vec<(String, Option<f64>)> {
+    match value {
+        JsonValue::Array(members) => members.iter().flat_map(values).collect(),
+        JsonValue::Null => vec![],
+        JsonValue::Number(_) => vec![(value.dump(), value.as_f64())],
+        _ if value.is_string() => vec![(value.to_string(), None)],
+        _ => vec![(value.dump(), None)],
+    }
+}
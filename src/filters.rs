@@ -0,0 +1,602 @@
+// Algolia filter expressions
+// `filters` is parsed into a Filter tree, the legacy facetFilters,
+// numericFilters and tagFilters arrays are turned into the same tree.
+// the tree compiles to SQL conditions on the document rowid, against the
+// facets and typed attributes side tables. every value is bound, never formatted
+use crate::attributes;
+use crate::index_engine::{IndexError, ATTRIBUTES_TABLE, FACETS_TABLE};
+use json::JsonValue;
+
+// _tags values are always filterable
+pub const TAGS: &str = "_tags";
+// typed values numeric filters compare with
+const NUMERIC: &str = "typeof(value) IN ('integer', 'real')";
+// nested NOTs and parentheses the parser accepts, it recurses on each of them
+const MAX_DEPTH: usize = 64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    // attribute:value, matched against the facet values
+    Facet {
+        attribute: String,
+        value: String,
+    },
+    // attribute < 10, any numeric value of the attribute. booleans are 0/1
+    Numeric {
+        attribute: String,
+        operator: &'static str,
+        value: f64,
+    },
+    // attribute:10 TO 20, bounds included
+    Range {
+        attribute: String,
+        from: f64,
+        to: f64,
+    },
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Colon,
+    Operator(&'static str),
+    Word(String),
+    Quoted(String),
+}
+
+// parses a `filters` expression:
+// brand:Apple AND (price < 100 OR onSale:true) AND NOT category:refurbished
+pub fn parse(expression: &str) -> Result<Filter, IndexError> {
+    let mut parser = Parser {
+        tokens: tokenize(expression)?,
+        position: 0,
+        depth: 0,
+    };
+    let filter = parser.or()?;
+    match parser.next() {
+        None => Ok(filter),
+        Some(token) => Err(invalid_filter(&format!("unexpected {:?}", token))),
+    }
+}
+
+// facetFilters, numericFilters and tagFilters: a list whose entries are
+// ANDed, an entry being a filter or a list of filters to OR. None when empty
+pub fn from_legacy(key: &str, value: &JsonValue) -> Result<Option<Filter>, IndexError> {
+    let mut groups = vec![];
+    for group in legacy_groups(value)? {
+        let mut filters = group
+            .iter()
+            .map(|filter| match key {
+                "facetFilters" => facet_filter(filter),
+                "tagFilters" => Ok(tag_filter(filter)),
+                _ => parse(filter),
+            })
+            .collect::<Result<Vec<Filter>, IndexError>>()?;
+        match filters.len() {
+            0 => (),
+            1 => groups.push(filters.remove(0)),
+            _ => groups.push(Filter::Or(filters)),
+        }
+    }
+    Ok(match groups.len() {
+        0 => None,
+        1 => Some(groups.remove(0)),
+        _ => Some(Filter::And(groups)),
+    })
+}
+
+impl Filter {
+    // an SQL condition on `rowid`. values are added to `binds` as named
+    // parameters, :filter0, :filter1...
+    pub fn to_sql(&self, rowid: &str, binds: &mut Vec<(String, sqlite::Value)>) -> String {
+        match self {
+            Filter::Facet { attribute, value } => {
+                let attribute = bind(binds, sqlite::Value::String(attribute.clone()));
+                let value = bind(binds, sqlite::Value::String(value.clone()));
+                format!(
+                    "{} IN (SELECT id FROM {} WHERE attribute = {} AND value = {} COLLATE NOCASE)",
+                    rowid, FACETS_TABLE, attribute, value
+                )
+            }
+            Filter::Numeric {
+                attribute,
+                operator,
+                value,
+            } => {
+                let attribute = bind(binds, sqlite::Value::String(attribute.clone()));
+                let value = bind(binds, sqlite::Value::Float(*value));
+                // != holds for documents without any value equal to it
+                let (membership, operator) = match *operator {
+                    "!=" => ("NOT IN", "="),
+                    operator => ("IN", operator),
+                };
+                format!(
+                    "{} {} (SELECT id FROM {} WHERE attribute = {} AND {} AND value {} {})",
+                    rowid, membership, ATTRIBUTES_TABLE, attribute, NUMERIC, operator, value
+                )
+            }
+            Filter::Range {
+                attribute,
+                from,
+                to,
+            } => {
+                let attribute = bind(binds, sqlite::Value::String(attribute.clone()));
+                let from = bind(binds, sqlite::Value::Float(*from));
+                let to = bind(binds, sqlite::Value::Float(*to));
+                format!(
+                    "{} IN (SELECT id FROM {} WHERE attribute = {} AND {} AND value BETWEEN {} AND {})",
+                    rowid, ATTRIBUTES_TABLE, attribute, NUMERIC, from, to
+                )
+            }
+            Filter::And(filters) => join(filters, " AND ", rowid, binds),
+            Filter::Or(filters) => join(filters, " OR ", rowid, binds),
+            Filter::Not(filter) => format!("NOT ({})", filter.to_sql(rowid, binds)),
+        }
+    }
+
+    // attributes compared with facet values
+    pub fn facet_attributes(&self) -> Vec<&str> {
+        match self {
+            Filter::Facet { attribute, .. } => vec![attribute.as_str()],
+            Filter::Numeric { .. } | Filter::Range { .. } => vec![],
+            Filter::And(filters) | Filter::Or(filters) => {
+                filters.iter().flat_map(|f| f.facet_attributes()).collect()
+            }
+            Filter::Not(filter) => filter.facet_attributes(),
+        }
+    }
+}
+
+fn bind(binds: &mut Vec<(String, sqlite::Value)>, value: sqlite::Value) -> String {
+    let name = format!(":filter{}", binds.len());
+    binds.push((name.clone(), value));
+    name
+}
+
+fn join(
+    filters: &[Filter],
+    separator: &str,
+    rowid: &str,
+    binds: &mut Vec<(String, sqlite::Value)>,
+) -> String {
+    let conditions: Vec<String> = filters.iter().map(|f| f.to_sql(rowid, binds)).collect();
+    format!("({})", conditions.join(separator))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        if self.peek() == Some(&Token::Word(keyword.to_string())) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn or(&mut self) -> Result<Filter, IndexError> {
+        let mut filters = vec![self.and()?];
+        while self.keyword("OR") {
+            filters.push(self.and()?);
+        }
+        Ok(match filters.len() {
+            1 => filters.remove(0),
+            _ => Filter::Or(filters),
+        })
+    }
+
+    fn and(&mut self) -> Result<Filter, IndexError> {
+        let mut filters = vec![self.unary()?];
+        while self.keyword("AND") {
+            filters.push(self.unary()?);
+        }
+        Ok(match filters.len() {
+            1 => filters.remove(0),
+            _ => Filter::And(filters),
+        })
+    }
+
+    fn unary(&mut self) -> Result<Filter, IndexError> {
+        if self.keyword("NOT") {
+            return self.nested(|parser| Ok(Filter::Not(Box::new(parser.unary()?))));
+        }
+        if self.peek() == Some(&Token::Open) {
+            self.position += 1;
+            return self.nested(|parser| {
+                let filter = parser.or()?;
+                match parser.next() {
+                    Some(Token::Close) => Ok(filter),
+                    _ => Err(invalid_filter("missing closing parenthesis")),
+                }
+            });
+        }
+        self.predicate()
+    }
+
+    // parses one more level of nesting, up to MAX_DEPTH
+    fn nested<F>(&mut self, parse: F) -> Result<Filter, IndexError>
+    where
+        F: FnOnce(&mut Self) -> Result<Filter, IndexError>,
+    {
+        if self.depth == MAX_DEPTH {
+            return Err(invalid_filter("too deeply nested"));
+        }
+        self.depth += 1;
+        let filter = parse(self);
+        self.depth -= 1;
+        filter
+    }
+
+    fn predicate(&mut self) -> Result<Filter, IndexError> {
+        let attribute = attributes::path(&self.value("attribute")?);
+        match self.next() {
+            Some(Token::Colon) => {
+                let value = self.value("value")?;
+                if self.keyword("TO") {
+                    let to = self.value("upper bound")?;
+                    return Ok(Filter::Range {
+                        attribute,
+                        from: number(&value)?,
+                        to: number(&to)?,
+                    });
+                }
+                Ok(Filter::Facet { attribute, value })
+            }
+            Some(Token::Operator(operator)) => {
+                let value = self.value("value")?;
+                Ok(Filter::Numeric {
+                    attribute,
+                    operator,
+                    value: number(&value)?,
+                })
+            }
+            _ => Err(invalid_filter(&format!(
+                "expected ':' or an operator after {}",
+                attribute
+            ))),
+        }
+    }
+
+    fn value(&mut self, what: &str) -> Result<String, IndexError> {
+        match self.next() {
+            Some(Token::Word(word)) if !["AND", "OR", "NOT", "TO"].contains(&word.as_str()) => {
+                Ok(word)
+            }
+            Some(Token::Quoted(value)) => Ok(value),
+            _ => Err(invalid_filter(&format!("expected {}", what))),
+        }
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, IndexError> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            _ if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(Token::Open);
+                i += 1
+            }
+            ')' => {
+                tokens.push(Token::Close);
+                i += 1
+            }
+            ':' => {
+                tokens.push(Token::Colon);
+                i += 1
+            }
+            '<' | '>' | '=' | '!' => {
+                let operator = match (c, chars.get(i + 1)) {
+                    ('<', Some('=')) => "<=",
+                    ('>', Some('=')) => ">=",
+                    ('!', Some('=')) => "!=",
+                    ('<', _) => "<",
+                    ('>', _) => ">",
+                    ('=', _) => "=",
+                    _ => return Err(invalid_filter("'!' must be followed by '='")),
+                };
+                tokens.push(Token::Operator(operator));
+                i += operator.len();
+            }
+            '"' | '\'' => {
+                let mut value = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(invalid_filter("unterminated string")),
+                        Some('\\') if i + 1 < chars.len() => {
+                            value.push(chars[i + 1]);
+                            i += 2;
+                        }
+                        Some(&q) if q == c => {
+                            i += 1;
+                            break;
+                        }
+                        Some(&other) => {
+                            value.push(other);
+                            i += 1;
+                        }
+                    }
+                }
+                tokens.push(Token::Quoted(value));
+            }
+            _ => {
+                let start = i;
+                while i < chars.len()
+                    && !chars[i].is_whitespace()
+                    && !"()\"':<>=!".contains(chars[i])
+                {
+                    i += 1;
+                }
+                tokens.push(Token::Word(chars[start..i].iter().collect()));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// the groups of a legacy filter list. accepts a JSON array (or its text, as
+// sent in `params`) or the string form "a,(b,c)"
+fn legacy_groups(value: &JsonValue) -> Result<Vec<Vec<String>>, IndexError> {
+    if let Some(text) = value.as_str() {
+        return match json::parse(text) {
+            Ok(parsed) if parsed.is_array() => legacy_groups(&parsed),
+            _ => Ok(split_legacy(text)),
+        };
+    }
+    if !value.is_array() {
+        return Err(invalid_filter(&value.dump()));
+    }
+
+    let mut groups = vec![];
+    for member in value.members() {
+        if let Some(filter) = member.as_str() {
+            groups.push(vec![filter.to_string()]);
+        } else if member.is_array() && member.members().all(|m| m.is_string()) {
+            groups.push(member.members().map(|m| m.to_string()).collect());
+        } else {
+            return Err(invalid_filter(&member.dump()));
+        }
+    }
+    Ok(groups)
+}
+
+fn split_legacy(text: &str) -> Vec<Vec<String>> {
+    let mut groups = vec![];
+    let mut depth = 0;
+    let mut current = String::new();
+    for c in text.chars().chain(std::iter::once(',')) {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth <= 0 => {
+                let group: Vec<String> = current
+                    .split(',')
+                    .map(|f| f.trim().to_string())
+                    .filter(|f| !f.is_empty())
+                    .collect();
+                if !group.is_empty() {
+                    groups.push(group);
+                }
+                current.clear();
+            }
+            _ => current.push(c),
+        }
+    }
+    groups
+}
+
+// "brand:Apple", "brand:-Apple" to exclude, "brand:\-Apple" for a leading dash
+fn facet_filter(filter: &str) -> Result<Filter, IndexError> {
+    let (attribute, value) = filter
+        .split_once(':')
+        .ok_or_else(|| invalid_filter(filter))?;
+    let attribute = attributes::path(attribute.trim());
+    match value.strip_prefix('-') {
+        Some(value) => Ok(Filter::Not(Box::new(Filter::Facet {
+            attribute,
+            value: value.to_string(),
+        }))),
+        None => Ok(Filter::Facet {
+            attribute,
+            value: value.strip_prefix('\\').unwrap_or(value).to_string(),
+        }),
+    }
+}
+
+// "tag", "-tag" to exclude
+fn tag_filter(filter: &str) -> Filter {
+    match filter.strip_prefix('-') {
+        Some(tag) => Filter::Not(Box::new(Filter::Facet {
+            attribute: TAGS.to_string(),
+            value: tag.to_string(),
+        })),
+        None => Filter::Facet {
+            attribute: TAGS.to_string(),
+            value: filter.to_string(),
+        },
+    }
+}
+
+fn number(value: &str) -> Result<f64, IndexError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| invalid_filter(&format!("{} is not a number", value)))
+}
+
+fn invalid_filter(message: &str) -> IndexError {
+    IndexError::InvalidParameter(format!("Invalid filter: {}", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn facet(attribute: &str, value: &str) -> Filter {
+        Filter::Facet {
+            attribute: attribute.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn numeric(attribute: &str, operator: &'static str, value: f64) -> Filter {
+        Filter::Numeric {
+            attribute: attribute.to_string(),
+            operator,
+            value,
+        }
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(
+            parse("a:1 OR b:2 AND c:3").unwrap(),
+            Filter::Or(vec![
+                facet("a", "1"),
+                Filter::And(vec![facet("b", "2"), facet("c", "3")]),
+            ])
+        );
+        assert_eq!(
+            parse("(a:1 OR b:2) AND c:3").unwrap(),
+            Filter::And(vec![
+                Filter::Or(vec![facet("a", "1"), facet("b", "2")]),
+                facet("c", "3"),
+            ])
+        );
+    }
+
+    #[test]
+    fn not_applies_to_the_next_operand() {
+        assert_eq!(
+            parse("NOT a:1 AND b:2").unwrap(),
+            Filter::And(vec![
+                Filter::Not(Box::new(facet("a", "1"))),
+                facet("b", "2"),
+            ])
+        );
+        assert_eq!(
+            parse("NOT NOT a:1").unwrap(),
+            Filter::Not(Box::new(Filter::Not(Box::new(facet("a", "1")))))
+        );
+    }
+
+    #[test]
+    fn numeric_comparisons_and_ranges() {
+        assert_eq!(parse("price<=10").unwrap(), numeric("price", "<=", 10.0));
+        assert_eq!(parse("price != 2.5").unwrap(), numeric("price", "!=", 2.5));
+        assert_eq!(
+            parse("price:10 TO 20").unwrap(),
+            Filter::Range {
+                attribute: "price".to_string(),
+                from: 10.0,
+                to: 20.0,
+            }
+        );
+        assert!(parse("price:10 TO cheap").is_err());
+        assert!(parse("price < cheap").is_err());
+    }
+
+    #[test]
+    fn quoted_values() {
+        assert_eq!(
+            parse(r#"brand:"Procter & Gamble""#).unwrap(),
+            facet("brand", "Procter & Gamble")
+        );
+        assert_eq!(parse("brand:'AND'").unwrap(), facet("brand", "AND"));
+        assert_eq!(
+            parse(r#"title:"say \"hi\"""#).unwrap(),
+            facet("title", r#"say "hi""#)
+        );
+        assert!(parse(r#"brand:"Apple"#).is_err());
+    }
+
+    #[test]
+    fn attribute_paths() {
+        assert_eq!(
+            parse("authors[].name:Ann").unwrap(),
+            facet("authors.name", "Ann")
+        );
+    }
+
+    #[test]
+    fn malformed_expressions() {
+        for expression in ["", "a:1 AND", "(a:1", "a:1)", "a", "a:1 b:2", "a ! 1"] {
+            assert!(parse(expression).is_err(), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| format!("{}a:1{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(parse(&nested(MAX_DEPTH + 1)).is_err());
+
+        assert!(parse(&format!("{}a:1", "NOT ".repeat(MAX_DEPTH))).is_ok());
+        let too_deep = format!("{}brand:Apple", "NOT ".repeat(50_000));
+        match parse(&too_deep) {
+            Err(e) => assert_eq!(e.to_string(), "Invalid filter: too deeply nested"),
+            Ok(_) => panic!("parsed {} nested NOTs", 50_000),
+        }
+    }
+
+    #[test]
+    fn legacy_facet_filters() {
+        let value = json::parse(r#"["brand:Apple", ["color:red", "color:-blue"]]"#).unwrap();
+        assert_eq!(
+            from_legacy("facetFilters", &value).unwrap(),
+            Some(Filter::And(vec![
+                facet("brand", "Apple"),
+                Filter::Or(vec![
+                    facet("color", "red"),
+                    Filter::Not(Box::new(facet("color", "blue"))),
+                ]),
+            ]))
+        );
+        assert_eq!(
+            from_legacy("facetFilters", &"brand:\\-Apple".into()).unwrap(),
+            Some(facet("brand", "-Apple"))
+        );
+        assert_eq!(from_legacy("facetFilters", &json::array![]).unwrap(), None);
+        assert!(from_legacy("facetFilters", &json::array![1]).is_err());
+    }
+
+    #[test]
+    fn legacy_string_forms() {
+        assert_eq!(
+            from_legacy("tagFilters", &"a,(b,-c)".into()).unwrap(),
+            Some(Filter::And(vec![
+                facet(TAGS, "a"),
+                Filter::Or(vec![
+                    facet(TAGS, "b"),
+                    Filter::Not(Box::new(facet(TAGS, "c"))),
+                ]),
+            ]))
+        );
+        assert_eq!(
+            from_legacy("numericFilters", &r#"["price < 10"]"#.into()).unwrap(),
+            Some(numeric("price", "<", 10.0))
+        );
+    }
+}
@@ -0,0 +1,150 @@
+// FTS5 query string building
+// user text is never handed to MATCH as-is: every term becomes a quoted
+// FTS5 string so operators, column filters and quotes lose their meaning.
+// the tokenizer still splits punctuation inside a term, so "foo@bar.com"
+// or "well-known" are matched as phrases instead of being stripped.
+
+// quotes a single term as an FTS5 string, doubling embedded quotes
+pub fn quote_term(term: &str) -> String {
+    format!("\"{}\"", term.replace('"', "\"\""))
+}
+
+// splits a raw query into terms that can produce at least one token
+pub fn terms(qs: &str) -> Vec<String> {
+    qs.split_whitespace()
+        .filter(|term| term.chars().any(|c| c.is_alphanumeric()))
+        .map(|term| term.to_string())
+        .collect()
+}
+
+// builds a MATCH expression from groups of alternatives, see synonyms.rs:
+// every group is required, any of its alternatives will do. alternatives
+// of several words are matched as phrases.
+// returns None when there is nothing searchable (empty query, punctuation only)
+pub fn expression(groups: &[Vec<String>]) -> Option<String> {
+    if groups.is_empty() {
+        return None;
+    }
+
+    Some(
+        groups
+            .iter()
+            .map(|alternatives| match alternatives.as_slice() {
+                [term] => quote_term(term),
+                _ => format!(
+                    "({})",
+                    alternatives
+                        .iter()
+                        .map(|alternative| quote_term(alternative))
+                        .collect::<Vec<String>>()
+                        .join(" OR ")
+                ),
+            })
+            .collect::<Vec<String>>()
+            .join(" AND "),
+    )
+}
+
+// limits a MATCH expression to some columns with an FTS5 column filter
+pub fn restrict_columns(expression: &str, columns: &[&String]) -> String {
+    let columns: Vec<String> = columns.iter().map(|column| quote_term(column)).collect();
+    format!("{{{}}} : ({})", columns.join(" "), expression)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(qs: &str) -> Option<String> {
+        let groups: Vec<Vec<String>> = terms(qs).into_iter().map(|term| vec![term]).collect();
+        expression(&groups)
+    }
+
+    // the rowids of the documents an expression matches, in a real FTS5 table
+    fn matches(expression: &str) -> Vec<i64> {
+        let connection = sqlite::open(":memory:").unwrap();
+        connection
+            .execute(
+                "CREATE VIRTUAL TABLE docs USING fts5 (title);
+                 INSERT INTO docs (rowid, title) VALUES (1, 'c++ primer'), (2, 'near the sea'),
+                 (3, 'he said \"hi\"'), (4, 'title: star wars'), (5, 'plain text');",
+            )
+            .unwrap();
+        let mut statement = connection
+            .prepare("SELECT rowid FROM docs WHERE docs MATCH ? ORDER BY rowid")
+            .unwrap()
+            .bind(1, expression)
+            .unwrap();
+        let mut rowids = vec![];
+        while let sqlite::State::Row = statement.next().unwrap() {
+            rowids.push(statement.read::<i64>(0).unwrap());
+        }
+        rowids
+    }
+
+    #[test]
+    fn quotes_are_doubled() {
+        assert_eq!(quote_term("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(query("\"hi\"").unwrap(), "\"\"\"hi\"\"\"");
+        assert_eq!(matches(&query("\"hi\"").unwrap()), vec![3]);
+        // an unbalanced quote is no syntax error
+        assert_eq!(matches(&query("said \"hi").unwrap()), vec![3]);
+    }
+
+    #[test]
+    fn operators_are_plain_text() {
+        assert_eq!(query("sea*").unwrap(), "\"sea*\"");
+        assert_eq!(matches(&query("pla*").unwrap()), Vec::<i64>::new());
+        assert_eq!(matches(&query("^plain").unwrap()), vec![5]);
+        assert_eq!(matches(&query("NEAR sea").unwrap()), vec![2]);
+        assert_eq!(
+            matches(&query("near(sea text)").unwrap()),
+            Vec::<i64>::new()
+        );
+        assert_eq!(matches(&query("text OR sea").unwrap()), Vec::<i64>::new());
+        assert_eq!(matches(&query("NOT sea").unwrap()), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn column_filters_are_plain_text() {
+        assert_eq!(query("title:star").unwrap(), "\"title:star\"");
+        assert_eq!(matches(&query("title:star").unwrap()), vec![4]);
+        assert_eq!(matches(&query("title: star").unwrap()), vec![4]);
+        assert_eq!(matches(&query("{title}:plain").unwrap()), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn punctuation_inside_a_term_makes_a_phrase() {
+        assert_eq!(terms("c++ primer"), vec!["c++", "primer"]);
+        assert_eq!(matches(&query("c++").unwrap()), vec![1]);
+        assert_eq!(matches(&query("c++ primer").unwrap()), vec![1]);
+    }
+
+    #[test]
+    fn nothing_searchable_gives_no_expression() {
+        assert_eq!(query(""), None);
+        assert_eq!(query("   "), None);
+        assert_eq!(query("* ^ : \" ++"), None);
+        assert!(terms("* ^ :").is_empty());
+    }
+
+    #[test]
+    fn sql_is_never_executed() {
+        let expression = query("amigos '; select * from docs; --").unwrap();
+        assert_eq!(matches(&expression), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn column_restrictions_quote_the_columns() {
+        let title = "title".to_string();
+        let odd = "a\"b".to_string();
+        assert_eq!(
+            restrict_columns("\"star\"", &[&title, &odd]),
+            "{\"title\" \"a\"\"b\"} : (\"star\")"
+        );
+        assert_eq!(
+            matches(&restrict_columns(&query("star").unwrap(), &[&title])),
+            vec![4]
+        );
+    }
+}
@@ -1,4 +1,5 @@
-use actix_web::{get, post, Error, Result};
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, put, Error, Result};
 use actix_web::{web, HttpResponse};
 use chrono::DateTime;
 use chrono::Utc;
@@ -6,15 +7,18 @@ use json::object;
 use json::JsonValue;
 use std::time::SystemTime;
 
-use regex::Regex;
+use crate::batch::BatchAction;
+use crate::browse::{self, Cursor};
+use crate::index_engine::{IndexError, SearchResult};
+use crate::index_manager::{self, IndexManager, ObjectRequest};
+use crate::search_params::{parse_list_parameter, SearchParams};
+use crate::synonyms::{self, Synonym};
+use crate::tasks::{self, Task};
+
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::sync::Mutex;
 
-#[derive(Deserialize)]
-pub struct Query {
-    q: String,
-}
-
 #[derive(Deserialize, Clone)]
 struct PathInfo {
     route: String,
@@ -25,6 +29,76 @@ struct DocumentInfo {
     index: String,
 }
 
+#[derive(Deserialize)]
+struct FacetInfo {
+    index: String,
+    facet: String,
+}
+
+#[derive(Deserialize)]
+struct TaskInfo {
+    index: String,
+    task_id: u64,
+}
+
+#[derive(Deserialize)]
+struct ObjectInfo {
+    index: String,
+    object_id: String,
+}
+
+// per-document errors are returned to the client instead of only being logged
+fn index_error_response(e: &IndexError) -> HttpResponse {
+    info!("index error: {}", e);
+    HttpResponse::build(
+        StatusCode::from_u16(e.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+    )
+    .content_type("application/json")
+    .body(e.to_json().dump())
+}
+
+fn parse_body(body: &web::Bytes) -> Result<JsonValue, IndexError> {
+    std::str::from_utf8(body)
+        .map_err(|e| IndexError::InvalidDocument(e.to_string()))
+        .and_then(|body| json::parse(body).map_err(|e| IndexError::InvalidDocument(e.to_string())))
+}
+
+fn now_rfc3339() -> String {
+    let now: DateTime<Utc> = SystemTime::now().into();
+    now.to_rfc3339()
+}
+
+// queues a write on an index, creating it when needed, and wakes up its
+// worker. returns the taskID. the manager lock is only held to find the index
+fn queue_task(
+    index_manager: &Mutex<IndexManager>,
+    index_name: &str,
+    task: Task,
+) -> Result<u64, IndexError> {
+    let (index, worker) = index_manager
+        .lock()
+        .unwrap()
+        .task_queue(index_name, &task)?;
+    let task_id = index.lock().unwrap().enqueue(&task)?;
+    // the worker only stops when its index is gone
+    let _ = worker.send(());
+    Ok(task_id)
+}
+
+// queues a batch, returning the objectIDs it touches and its taskID
+fn queue_batch(
+    index_manager: &Mutex<IndexManager>,
+    index_name: &str,
+    mut actions: Vec<BatchAction>,
+) -> Result<(Vec<String>, u64), IndexError> {
+    let object_ids = actions
+        .iter_mut()
+        .filter_map(|action| action.assign_object_id())
+        .collect();
+    let task_id = queue_task(index_manager, index_name, Task::Batch(actions))?;
+    Ok((object_ids, task_id))
+}
+
 // stats route per index:
 // top queries with more results, top queries w/o result, top queries with less results
 // top terms
@@ -33,64 +107,177 @@ struct DocumentInfo {
 #[get("/{route:.*}")]
 async fn catch_get(info: web::Path<PathInfo>) -> Result<HttpResponse, Error> {
     info!("{}", info.route);
-    return Ok(HttpResponse::Ok()
+    Ok(HttpResponse::Ok()
         .content_type("application/json")
-        .body(info.clone().route));
+        .body(info.clone().route))
 }
 
 #[post("/1/indexes/{route}/query")]
 async fn query_index(
     info: web::Path<PathInfo>,
     index_manager: web::Data<Mutex<crate::index_manager::IndexManager>>,
+    stats: web::Data<Mutex<crate::stats::SearchStats>>,
     body: web::Bytes,
 ) -> Result<HttpResponse, Error> {
-    let result = json::parse(std::str::from_utf8(&body).unwrap());
-    let data = index_manager.lock().unwrap();
+    let injson = match parse_body(&body) {
+        Ok(v) => v,
+        Err(e) => return Ok(index_error_response(&e)),
+    };
     info!("index: {}", info.route);
-    info!("body: {:?}", result);
+    info!("body: {}", injson);
 
-    let injson: JsonValue = match result {
-        Ok(v) => v,
-        Err(e) => json::object! {"err" => e.to_string() },
+    let params = match SearchParams::from_request(&injson) {
+        Ok(params) => params,
+        Err(e) => return Ok(index_error_response(&e)),
     };
+    match search_by_name(&index_manager, &info.route, &params) {
+        Ok(rs) => {
+            record_search(&stats, &info.route, &rs);
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(rs.to_json().dump()))
+        }
+        Err(e) => {
+            record_error(&stats, &info.route, &e);
+            Ok(index_error_response(&e))
+        }
+    }
+}
 
-    if !injson["query"].is_null() {
-        let query = injson["query"].clone().to_string();
-        let re = Regex::new(r"\W+").unwrap();
-        let caps: Vec<&str> = re.split(&query).collect();
-        let query = caps.join(" ");
-
-        let index_name = info.route.clone();
-        let index = data.index.get(&index_name);
-
-        match index {
-            Some(index_engine) => match index_engine.lock() {
-                Ok(mut p) => {
-                    let pp = p.search(query);
-                    let rs = object! {
-                        hits: pp.unwrap(),
-                    };
-                    return Ok(HttpResponse::Ok()
-                        .content_type("application/json")
-                        .body(rs.to_string()));
-                }
-                Err(e) => {
-                    return Ok(HttpResponse::NoContent()
-                        .content_type("application/json")
-                        .body(e.to_string()))
-                }
-            },
-            None => {
-                return Ok(HttpResponse::NotFound()
-                    .content_type("application/json")
-                    .body(format!("msg: index [{:?}] not found", index_name)))
+// deletes every record matching a query and/or filters, Algolia's deleteBy.
+// {"params": "filters=..."} or the parameters as keys. the records are
+// matched when the task is applied, after the writes queued before it
+#[post("/1/indexes/{index}/deleteByQuery")]
+async fn delete_by_query(
+    info: web::Path<DocumentInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+    stats: web::Data<Mutex<crate::stats::SearchStats>>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let result = parse_body(&body)
+        .and_then(|injson| SearchParams::from_request(&injson))
+        .and_then(|params| queue_delete_by_query(&index_manager, &info.index, params));
+    match result {
+        Ok(task_id) => {
+            stats
+                .lock()
+                .unwrap()
+                .increment_index_usage_counter(info.index.clone());
+            let rs = object! {
+                updatedAt: now_rfc3339(),
+                taskID: task_id,
+            };
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(rs.dump()))
+        }
+        Err(e) => {
+            record_error(&stats, &info.index, &e);
+            Ok(index_error_response(&e))
+        }
+    }
+}
+
+fn queue_delete_by_query(
+    index_manager: &Mutex<IndexManager>,
+    index_name: &str,
+    params: SearchParams,
+) -> Result<u64, IndexError> {
+    if params.query.trim().is_empty() && params.filter().is_none() {
+        return Err(IndexError::InvalidParameter(
+            "deleteByQuery needs a query or filters, clear the index to delete every record"
+                .to_string(),
+        ));
+    }
+    if !index_manager.lock().unwrap().index.contains_key(index_name) {
+        return Err(IndexError::NotFound("Index does not exist".to_string()));
+    }
+    queue_task(
+        index_manager,
+        index_name,
+        Task::DeleteByQuery(Box::new(params)),
+    )
+}
+
+// walks the whole index, a batch per call. the response has a cursor
+// for the next batch until the last one
+#[post("/1/indexes/{index}/browse")]
+async fn browse_index(
+    info: web::Path<DocumentInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let injson = if body.is_empty() {
+        JsonValue::new_object()
+    } else {
+        match parse_body(&body) {
+            Ok(v) => v,
+            Err(e) => return Ok(index_error_response(&e)),
+        }
+    };
+    let (params, after) = match browse::from_request(&injson) {
+        Ok(request) => request,
+        Err(e) => return Ok(index_error_response(&e)),
+    };
+
+    let index = index_manager
+        .lock()
+        .unwrap()
+        .index
+        .get(&info.index)
+        .cloned();
+    let result = match index {
+        Some(index_engine) => index_engine.lock().unwrap().browse(&params, after),
+        None => Err(IndexError::NotFound("Index does not exist".to_string())),
+    };
+    match result {
+        Ok((rs, last)) => {
+            let mut rs = rs.to_json();
+            // matches are only counted for the first batch
+            if after > 0 {
+                rs.remove("nbHits");
+                rs.remove("nbPages");
             }
-        };
+            if let Some(after) = last {
+                let cursor = Cursor {
+                    params: params.to_query_string(),
+                    after,
+                };
+                rs["cursor"] = cursor.encode().into();
+            }
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(rs.dump()))
+        }
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
+
+#[post("/1/indexes/{index}/facets/{facet}/query")]
+async fn search_facet_values(
+    info: web::Path<FacetInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    // an empty body searches every value
+    let injson = if body.is_empty() {
+        JsonValue::new_object()
     } else {
-        // defaults to not found
-        return Ok(HttpResponse::NotFound()
+        match parse_body(&body) {
+            Ok(v) => v,
+            Err(e) => return Ok(index_error_response(&e)),
+        }
+    };
+    let params = match SearchParams::from_request(&injson) {
+        Ok(params) => params,
+        Err(e) => return Ok(index_error_response(&e)),
+    };
+
+    match facet_search_by_name(&index_manager, &info.index, &info.facet, &params) {
+        Ok(rs) => Ok(HttpResponse::Ok()
             .content_type("application/json")
-            .body(format!("route not found: {}", info.clone().route)));
+            .body(rs.dump())),
+        Err(e) => Ok(index_error_response(&e)),
     }
 }
 
@@ -100,148 +287,833 @@ async fn batch_index(
     index_manager: web::Data<Mutex<crate::index_manager::IndexManager>>,
     body: web::Bytes,
 ) -> Result<HttpResponse, Error> {
-    let result = json::parse(std::str::from_utf8(&body).unwrap());
-    //let data = index_manager.lock().unwrap();
     debug!("route: {}", info.route);
     debug!("payload: {:?}", &body);
+    // a payload without requests is a bad request like any malformed one
+    let actions = match parse_body(&body)
+        .and_then(|injson| BatchAction::from_requests(&injson["requests"]))
+    {
+        Ok(actions) => actions,
+        Err(e) => return Ok(index_error_response(&e)),
+    };
+
+    match queue_batch(&index_manager, &info.route, actions) {
+        Ok((object_ids, task_id)) => {
+            let rs = object! {
+                updatedAt: now_rfc3339(),
+                taskID: task_id,
+                objectIDs: object_ids,
+            };
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(rs.dump()))
+        }
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
 
-    let injson: JsonValue = match result {
+// index settings. registered before the object routes, which would take
+// "settings" for an objectID
+#[get("/1/indexes/{index}/settings")]
+async fn get_settings(
+    info: web::Path<DocumentInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+) -> Result<HttpResponse, Error> {
+    let index = index_manager
+        .lock()
+        .unwrap()
+        .index
+        .get(&info.index)
+        .cloned();
+    match index {
+        Some(index_engine) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .body(index_engine.lock().unwrap().settings().dump())),
+        None => Ok(index_error_response(&IndexError::NotFound(
+            "Index does not exist".to_string(),
+        ))),
+    }
+}
+
+#[put("/1/indexes/{index}/settings")]
+async fn set_settings(
+    info: web::Path<DocumentInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let update = match parse_body(&body) {
         Ok(v) => v,
-        Err(e) => {
-            return Ok(HttpResponse::BadRequest()
+        Err(e) => return Ok(index_error_response(&e)),
+    };
+    match queue_task(&index_manager, &info.index, Task::Settings(update)) {
+        Ok(task_id) => {
+            let rs = object! {
+                updatedAt: now_rfc3339(),
+                taskID: task_id,
+            };
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(rs.dump()))
+        }
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
+
+// status of a write, polled by the clients' waitTask
+#[get("/1/indexes/{index}/task/{task_id}")]
+async fn get_task(
+    info: web::Path<TaskInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+) -> Result<HttpResponse, Error> {
+    let (index, removed_task_published) = {
+        let index_manager = index_manager.lock().unwrap();
+        (
+            index_manager.index.get(&info.index).cloned(),
+            index_manager.removed_task_published(&info.index, info.task_id),
+        )
+    };
+    let result = match (index, removed_task_published) {
+        (Some(index_engine), _) => index_engine.lock().unwrap().task_status(info.task_id),
+        (None, Some(true)) => Ok((tasks::PUBLISHED.to_string(), None)),
+        _ => Err(IndexError::NotFound("Task does not exist".to_string())),
+    };
+    match result {
+        Ok((status, error)) => {
+            let mut rs = object! {
+                status: status.as_str(),
+                pendingTask: status != tasks::PUBLISHED,
+            };
+            if let Some(error) = error {
+                rs["message"] = error.into();
+            }
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(rs.dump()))
+        }
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
+
+// algolia index routes
+#[get("/1/indexes")]
+async fn list_indexes(
+    index_manager: web::Data<Mutex<IndexManager>>,
+) -> Result<HttpResponse, Error> {
+    match index_manager::list(&index_manager) {
+        Ok(items) => {
+            let rs = object! {
+                items: items,
+                nbPages: 1,
+            };
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(rs.dump()))
+        }
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
+
+#[delete("/1/indexes/{index}")]
+async fn delete_index(
+    info: web::Path<DocumentInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+) -> Result<HttpResponse, Error> {
+    let result = index_manager::delete_index(&index_manager, &info.index);
+    match result {
+        Ok(task_id) => {
+            let rs = object! {
+                deletedAt: now_rfc3339(),
+                taskID: task_id,
+            };
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(rs.dump()))
+        }
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
+
+// drops the records, settings are kept
+#[post("/1/indexes/{index}/clear")]
+async fn clear_index(
+    info: web::Path<DocumentInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+) -> Result<HttpResponse, Error> {
+    match queue_batch(&index_manager, &info.index, vec![BatchAction::Clear]) {
+        Ok((_, task_id)) => {
+            let rs = object! {
+                updatedAt: now_rfc3339(),
+                taskID: task_id,
+            };
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(rs.dump()))
+        }
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
+
+// {"operation": "copy" | "move", "destination": "...", "scope": ["settings"]}
+#[post("/1/indexes/{index}/operation")]
+async fn index_operation(
+    info: web::Path<DocumentInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let injson = match parse_body(&body) {
+        Ok(v) => v,
+        Err(e) => return Ok(index_error_response(&e)),
+    };
+    let destination = match injson["destination"].as_str() {
+        Some(destination) => destination,
+        None => {
+            return Ok(index_error_response(&IndexError::InvalidParameter(
+                "destination is required".to_string(),
+            )))
+        }
+    };
+    let scope = match parse_scope(&injson["scope"]) {
+        Ok(scope) => scope,
+        Err(e) => return Ok(index_error_response(&e)),
+    };
+
+    let result = match injson["operation"].as_str() {
+        Some("copy") => index_manager::copy_index(
+            &index_manager,
+            &info.index,
+            destination,
+            scope.as_deref(),
+            &injson,
+        ),
+        Some("move") => {
+            index_manager::move_index(&index_manager, &info.index, destination, &injson)
+        }
+        _ => Err(IndexError::InvalidParameter(
+            "operation must be copy or move".to_string(),
+        )),
+    };
+    match result {
+        Ok(task_id) => {
+            let rs = object! {
+                updatedAt: now_rfc3339(),
+                taskID: task_id,
+            };
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(rs.dump()))
+        }
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
+
+// the parts of an index a copy is restricted to, None for all of it
+fn parse_scope(scope: &JsonValue) -> Result<Option<Vec<String>>, IndexError> {
+    if scope.is_null() {
+        return Ok(None);
+    }
+    let parts: Vec<String> = scope.members().map(|part| part.to_string()).collect();
+    if !scope.is_array()
+        || parts
+            .iter()
+            .any(|part| !["settings", "synonyms", "rules"].contains(&part.as_str()))
+    {
+        return Err(IndexError::InvalidParameter(format!(
+            "Invalid scope: {}",
+            scope.dump()
+        )));
+    }
+    Ok(Some(parts))
+}
+
+// algolia synonym routes
+#[get("/1/indexes/{index}/synonyms/{object_id}")]
+async fn get_synonym(
+    info: web::Path<ObjectInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+) -> Result<HttpResponse, Error> {
+    let index = index_manager
+        .lock()
+        .unwrap()
+        .index
+        .get(&info.index)
+        .cloned();
+    let result = match index {
+        Some(index_engine) => index_engine.lock().unwrap().get_synonym(&info.object_id),
+        None => Err(IndexError::NotFound("Index does not exist".to_string())),
+    };
+    match result {
+        Ok(synonym) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .body(synonym.dump())),
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
+
+#[put("/1/indexes/{index}/synonyms/{object_id}")]
+async fn save_synonym(
+    info: web::Path<ObjectInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+    query: web::Query<HashMap<String, String>>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let mut synonym = match parse_body(&body) {
+        Ok(v) => v,
+        Err(e) => return Ok(index_error_response(&e)),
+    };
+    if synonym["objectID"].is_null() {
+        synonym["objectID"] = info.object_id.clone().into();
+    } else if synonym["objectID"].as_str() != Some(info.object_id.as_str()) {
+        return Ok(index_error_response(&IndexError::InvalidParameter(
+            "objectID in the body and in the path differ".to_string(),
+        )));
+    }
+    let task = Synonym::from_json(&synonym).map(|synonym| Task::SaveSynonyms {
+        synonyms: vec![synonym],
+        replace: query
+            .get("replaceExistingSynonyms")
+            .is_some_and(|v| v == "true"),
+    });
+    match task.and_then(|task| queue_task(&index_manager, &info.index, task)) {
+        Ok(task_id) => {
+            let rs = object! {
+                updatedAt: now_rfc3339(),
+                taskID: task_id,
+                id: info.object_id.clone(),
+            };
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(rs.dump()))
+        }
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
+
+#[delete("/1/indexes/{index}/synonyms/{object_id}")]
+async fn delete_synonym(
+    info: web::Path<ObjectInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+) -> Result<HttpResponse, Error> {
+    let task = Task::DeleteSynonym(info.object_id.clone());
+    match queue_task(&index_manager, &info.index, task) {
+        Ok(task_id) => {
+            let rs = object! {
+                deletedAt: now_rfc3339(),
+                taskID: task_id,
+            };
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(rs.dump()))
+        }
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
+
+// [{synonym set}, ...], ?replaceExistingSynonyms=true drops the other sets
+#[post("/1/indexes/{index}/synonyms/batch")]
+async fn save_synonyms(
+    info: web::Path<DocumentInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+    query: web::Query<HashMap<String, String>>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let injson = match parse_body(&body) {
+        Ok(v) => v,
+        Err(e) => return Ok(index_error_response(&e)),
+    };
+    if !injson.is_array() {
+        return Ok(index_error_response(&IndexError::InvalidParameter(
+            "synonyms must be an array".to_string(),
+        )));
+    }
+    let task = injson
+        .members()
+        .map(Synonym::from_json)
+        .collect::<Result<Vec<Synonym>, IndexError>>()
+        .map(|synonyms| Task::SaveSynonyms {
+            synonyms,
+            replace: query
+                .get("replaceExistingSynonyms")
+                .is_some_and(|v| v == "true"),
+        });
+    match task.and_then(|task| queue_task(&index_manager, &info.index, task)) {
+        Ok(task_id) => {
+            let rs = object! {
+                updatedAt: now_rfc3339(),
+                taskID: task_id,
+            };
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(rs.dump()))
+        }
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
+
+#[post("/1/indexes/{index}/synonyms/clear")]
+async fn clear_synonyms(
+    info: web::Path<DocumentInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+) -> Result<HttpResponse, Error> {
+    match queue_task(&index_manager, &info.index, Task::ClearSynonyms) {
+        Ok(task_id) => {
+            let rs = object! {
+                updatedAt: now_rfc3339(),
+                taskID: task_id,
+            };
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(rs.dump()))
+        }
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
+
+// {"query": "...", "type": "synonym,placeholder", "page": 0, "hitsPerPage": 100}
+#[post("/1/indexes/{index}/synonyms/search")]
+async fn search_synonyms(
+    info: web::Path<DocumentInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let injson = if body.is_empty() {
+        JsonValue::new_object()
+    } else {
+        match parse_body(&body) {
+            Ok(v) => v,
+            Err(e) => return Ok(index_error_response(&e)),
+        }
+    };
+    let types = match parse_synonym_types(&injson["type"]) {
+        Ok(types) => types,
+        Err(e) => return Ok(index_error_response(&e)),
+    };
+    let page = injson["page"].as_u64().unwrap_or(0);
+    let hits_per_page = injson["hitsPerPage"]
+        .as_u64()
+        .unwrap_or(100)
+        .min(crate::search_params::MAX_HITS_PER_PAGE);
+
+    let index = index_manager
+        .lock()
+        .unwrap()
+        .index
+        .get(&info.index)
+        .cloned();
+    match index {
+        Some(index_engine) => {
+            let (hits, nb_hits) = index_engine.lock().unwrap().search_synonyms(
+                injson["query"].as_str().unwrap_or(""),
+                &types,
+                page,
+                hits_per_page,
+            );
+            let rs = object! {
+                hits: hits,
+                nbHits: nb_hits,
+            };
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(rs.dump()))
+        }
+        None => Ok(index_error_response(&IndexError::NotFound(
+            "Index does not exist".to_string(),
+        ))),
+    }
+}
+
+// synonym types, comma separated or as an array
+fn parse_synonym_types(types: &JsonValue) -> Result<Vec<&'static str>, IndexError> {
+    let names = match types.as_str() {
+        Some(types) => parse_list_parameter(types),
+        None => types.members().map(|t| t.to_string()).collect(),
+    };
+    names
+        .iter()
+        .map(|name| {
+            synonyms::parse_type(name).ok_or_else(|| {
+                IndexError::InvalidParameter(format!("Invalid synonym type: {}", name))
+            })
+        })
+        .collect()
+}
+
+// algolia single object routes
+#[get("/1/indexes/{index}/{object_id}")]
+async fn get_object(
+    info: web::Path<ObjectInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, Error> {
+    let attributes_to_retrieve = query
+        .get("attributesToRetrieve")
+        .map(|a| parse_list_parameter(a));
+    let index = index_manager
+        .lock()
+        .unwrap()
+        .index
+        .get(&info.index)
+        .cloned();
+
+    let result = match index {
+        Some(index_engine) => index_engine
+            .lock()
+            .unwrap()
+            .get_object(&info.object_id, attributes_to_retrieve.as_deref()),
+        None => Err(IndexError::NotFound("Index does not exist".to_string())),
+    };
+    match result {
+        Ok(doc) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .body(doc.dump())),
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
+
+#[put("/1/indexes/{index}/{object_id}")]
+async fn save_object(
+    info: web::Path<ObjectInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let mut doc = match parse_body(&body) {
+        Ok(doc) => doc,
+        Err(e) => return Ok(index_error_response(&e)),
+    };
+    if !doc.is_object() {
+        return Ok(index_error_response(&IndexError::InvalidDocument(
+            "document must be a JSON object".to_string(),
+        )));
+    }
+    doc["objectID"] = info.object_id.clone().into();
+
+    match queue_batch(
+        &index_manager,
+        &info.index,
+        vec![BatchAction::UpdateObject(doc)],
+    ) {
+        Ok((_, task_id)) => {
+            let rs = object! {
+                objectID: info.object_id.clone(),
+                updatedAt: now_rfc3339(),
+                taskID: task_id,
+            };
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(rs.dump()))
+        }
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
+
+#[delete("/1/indexes/{index}/{object_id}")]
+async fn delete_object(
+    info: web::Path<ObjectInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+) -> Result<HttpResponse, Error> {
+    match queue_batch(
+        &index_manager,
+        &info.index,
+        vec![BatchAction::DeleteObject(info.object_id.clone())],
+    ) {
+        Ok((_, task_id)) => {
+            let rs = object! {
+                objectID: info.object_id.clone(),
+                deletedAt: now_rfc3339(),
+                taskID: task_id,
+            };
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(rs.dump()))
+        }
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
+
+#[post("/1/indexes/{index}/{object_id}/partial")]
+async fn partial_update_object(
+    info: web::Path<ObjectInfo>,
+    index_manager: web::Data<Mutex<IndexManager>>,
+    query: web::Query<HashMap<String, String>>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let mut doc = match parse_body(&body) {
+        Ok(doc) => doc,
+        Err(e) => return Ok(index_error_response(&e)),
+    };
+    if !doc.is_object() {
+        return Ok(index_error_response(&IndexError::InvalidDocument(
+            "document must be a JSON object".to_string(),
+        )));
+    }
+    doc["objectID"] = info.object_id.clone().into();
+    let create_if_not_exists = query.get("createIfNotExists").is_none_or(|v| v != "false");
+
+    match queue_batch(
+        &index_manager,
+        &info.index,
+        vec![BatchAction::PartialUpdateObject {
+            body: doc,
+            create_if_not_exists,
+        }],
+    ) {
+        Ok((_, task_id)) => {
+            let rs = object! {
+                objectID: info.object_id.clone(),
+                updatedAt: now_rfc3339(),
+                taskID: task_id,
+            };
+            Ok(HttpResponse::Ok()
                 .content_type("application/json")
-                .body(format!("msg: error {:?}", e)))
-        } //json::object! {"err" => e.to_string() },
+                .body(rs.dump()))
+        }
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
+
+#[post("/1/indexes/*/objects")]
+async fn get_objects(
+    index_manager: web::Data<Mutex<IndexManager>>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let injson = match parse_body(&body) {
+        Ok(v) => v,
+        Err(e) => return Ok(index_error_response(&e)),
     };
+    if !injson["requests"].is_array() {
+        return Ok(index_error_response(&IndexError::InvalidDocument(
+            "requests must be an array".to_string(),
+        )));
+    }
 
-    if !injson["requests"].is_null() {
-        let request = injson["requests"].clone();
-        let index_name = info.route.clone();
-        info!("route ok");
-
-        let mut index_manager = index_manager.lock().unwrap();
-        let index = index_manager.index.get(&index_name);
-
-        info!("index ok");
-
-        match index {
-            Some(index_engine) => match index_engine.lock() {
-                Ok(mut ie) => {
-                    ie.index_string_document(request[0]["body"].to_string());
-
-                    let now = SystemTime::now();
-                    let now: DateTime<Utc> = now.into();
-                    let now = now.to_rfc3339();
-
-                    let rs = object! {
-                        updatedAt: now,
-                        taskID:1,
-                        objectID: "indexed by morocco",
-                    };
-                    return Ok(HttpResponse::Ok()
-                        .content_type("application/json")
-                        .body(rs.to_string()));
-                }
-                Err(e) => {
-                    return Ok(HttpResponse::BadRequest()
-                        .content_type("application/json")
-                        .body(format!("msg: err {:?}", e)))
-                }
+    let mut requests = vec![];
+    for request in injson["requests"].members() {
+        let attributes = &request["attributesToRetrieve"];
+        requests.push(ObjectRequest {
+            index_name: request["indexName"].to_string(),
+            object_id: request["objectID"].to_string(),
+            attributes_to_retrieve: if attributes.is_array() {
+                Some(attributes.members().map(|a| a.to_string()).collect())
+            } else if attributes.is_string() {
+                Some(parse_list_parameter(&attributes.to_string()))
+            } else {
+                None
             },
-            None => {
-                index_manager
-                    .create_new_index(index_name.to_string(), request[0]["body"].to_string())
-                    .unwrap(); // TODO: improve error handling
+        });
+    }
 
-                return Ok(HttpResponse::Ok()
-                    .content_type("application/json")
-                    .body(request[0]["body"].to_string()));
-            }
+    match index_manager::get_objects(&index_manager, &requests) {
+        Ok(results) => {
+            let rs = object! { results: results };
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(rs.dump()))
+        }
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
+
+// a single query of a multi-query call, resolved against its own index
+fn run_query(
+    index_manager: &Mutex<IndexManager>,
+    index_name: &str,
+    params: &SearchParams,
+) -> JsonValue {
+    let mut result = match search_by_name(index_manager, index_name, params) {
+        Ok(rs) => rs.to_json(),
+        Err(e) => {
+            let mut result = SearchResult::new(params).to_json();
+            result["message"] = e.to_string().into();
+            result
         }
     };
+    result["index"] = index_name.into();
+    result["processed"] = true.into();
+    result
+}
 
-    // defaults to not found
-    return Ok(HttpResponse::NotFound()
+// runs a search against an index by name.
+// the manager lock is only held to find the index
+fn search_by_name(
+    index_manager: &Mutex<IndexManager>,
+    index_name: &str,
+    params: &SearchParams,
+) -> Result<SearchResult, IndexError> {
+    let index = index_manager.lock().unwrap().index.get(index_name).cloned();
+    match index {
+        Some(index_engine) => index_engine.lock().unwrap().search(params),
+        None => Err(IndexError::NotFound("Index does not exist".to_string())),
+    }
+}
+
+// facet values search against an index by name
+fn facet_search_by_name(
+    index_manager: &Mutex<IndexManager>,
+    index_name: &str,
+    facet: &str,
+    params: &SearchParams,
+) -> Result<JsonValue, IndexError> {
+    let index = index_manager.lock().unwrap().index.get(index_name).cloned();
+    match index {
+        Some(index_engine) => index_engine
+            .lock()
+            .unwrap()
+            .search_facet_values(facet, params),
+        None => Err(IndexError::NotFound("Index does not exist".to_string())),
+    }
+}
+
+fn record_error(stats: &Mutex<crate::stats::SearchStats>, index_name: &str, e: &IndexError) {
+    let mut stats = stats.lock().unwrap();
+    if e.status() >= 500 {
+        stats.increment_http_5xx_errors_counter(index_name.to_string());
+    } else {
+        stats.increment_http_4xx_errors_counter(index_name.to_string());
+    }
+}
+
+fn record_search(stats: &Mutex<crate::stats::SearchStats>, index_name: &str, rs: &SearchResult) {
+    let mut stats = stats.lock().unwrap();
+    stats.increment_index_usage_counter(index_name.to_string());
+    if rs.nb_hits == 0 {
+        stats.increment_empty_result_counter(index_name.to_string());
+    } else {
+        stats.increment_result_counter(index_name.to_string());
+    }
+}
+
+#[post("/1/indexes/*/queries")]
+async fn multiple_queries(
+    index_manager: web::Data<Mutex<IndexManager>>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let injson = match parse_body(&body) {
+        Ok(v) => v,
+        Err(e) => return Ok(index_error_response(&e)),
+    };
+    if !injson["requests"].is_array() {
+        return Ok(index_error_response(&IndexError::InvalidDocument(
+            "requests must be an array".to_string(),
+        )));
+    }
+    let stop_if_enough_matches = injson["strategy"].as_str() == Some("stopIfEnoughMatches");
+
+    // every query is validated before any of them runs.
+    // queries with type "facet" search for values of their facet
+    let mut queries = vec![];
+    for request in injson["requests"].members() {
+        let facet = match request["type"].as_str() {
+            Some("facet") => Some(request["facet"].to_string()),
+            _ => None,
+        };
+        match SearchParams::from_request(request) {
+            Ok(params) => queries.push((request["indexName"].to_string(), facet, params)),
+            Err(e) => return Ok(index_error_response(&e)),
+        }
+    }
+
+    let results = run_queries(
+        queries,
+        stop_if_enough_matches,
+        |index_name, params| run_query(&index_manager, index_name, params),
+        |index_name, facet, params| match facet_search_by_name(
+            &index_manager,
+            index_name,
+            facet,
+            params,
+        ) {
+            Ok(result) => result,
+            Err(e) => object! { facetHits: [], message: e.to_string() },
+        },
+    );
+
+    let rs = object! { results: results };
+    Ok(HttpResponse::Ok()
         .content_type("application/json")
-        .body(format!("index/route not found: {}", info.clone().route)));
+        .body(rs.dump()))
+}
+
+// runs the queries of a multiple queries request in order. with
+// stopIfEnoughMatches, once the searches run have found hitsPerPage hits
+// between them, the next searches are not run and come back with
+// processed: false. facet queries always run
+fn run_queries(
+    queries: Vec<(String, Option<String>, SearchParams)>,
+    stop_if_enough_matches: bool,
+    mut search: impl FnMut(&str, &SearchParams) -> JsonValue,
+    mut facet_search: impl FnMut(&str, &str, &SearchParams) -> JsonValue,
+) -> Vec<JsonValue> {
+    let mut nb_hits: u64 = 0;
+    let mut enough_matches = false;
+    let mut results = vec![];
+    for (index_name, facet, params) in queries {
+        if let Some(facet) = facet {
+            results.push(facet_search(&index_name, &facet, &params));
+            continue;
+        }
+        if enough_matches {
+            let mut result = SearchResult::new(&params).to_json();
+            result["index"] = index_name.into();
+            result["processed"] = false.into();
+            results.push(result);
+            continue;
+        }
+
+        let result = search(&index_name, &params);
+        nb_hits = nb_hits.saturating_add(result["nbHits"].as_u64().unwrap_or(0));
+        if stop_if_enough_matches && nb_hits >= params.hits_per_page {
+            enough_matches = true;
+        }
+        results.push(result);
+    }
+    results
 }
 
 // rest search routes
 // resembles restmq on simplicity and routing
-// querystring is provided by the ?q= query parameter
+// querystring is provided by the ?q= query parameter, paging by ?page= and ?hitsPerPage=
 #[get("/i/{index}")]
 async fn search_index(
     info: web::Path<DocumentInfo>,
     index_manager: web::Data<Mutex<crate::index_manager::IndexManager>>,
     stats: web::Data<Mutex<crate::stats::SearchStats>>,
-    query: web::Query<Query>,
+    query: web::Query<HashMap<String, String>>,
 ) -> Result<HttpResponse, Error> {
-    let data = index_manager.lock().unwrap();
-    let index = data.index.get(&info.index);
-    let query = query.q.clone();
-    debug!("query string: {}", query);
-    let re = Regex::new(r"\W+").unwrap();
-    let caps: Vec<&str> = re.split(&query).collect();
-    let query = caps.join(" ");
-
-    debug!("filtered query string:{:?}", query);
+    let mut request = JsonValue::new_object();
+    for (key, value) in query.into_inner() {
+        match key.as_str() {
+            "q" => request["query"] = value.into(),
+            _ => request[key.as_str()] = value.into(),
+        }
+    }
+    let params = match SearchParams::from_request(&request) {
+        Ok(params) => params,
+        Err(e) => return Ok(index_error_response(&e)),
+    };
+    debug!("query string: {}", params.query);
 
-    match index {
-        Some(indexengine) => match indexengine.lock() {
-            Ok(mut ie) => match ie.search(query) {
-                Ok(payload) => {
-                    stats
-                        .lock()
-                        .unwrap()
-                        .increment_index_usage_counter(info.index.clone());
-                    return Ok(HttpResponse::Ok()
-                        .content_type("application/json")
-                        .body(payload));
-                }
-                Err(e) => {
-                    stats
-                        .lock()
-                        .unwrap()
-                        .increment_http_4xx_errors_counter(info.index.clone());
-                    return Ok(HttpResponse::NoContent()
-                        .content_type("application/json")
-                        .body(e.to_string()));
-                }
-            },
-            Err(e) => {
-                stats
-                    .lock()
-                    .unwrap()
-                    .increment_http_5xx_errors_counter(info.index.clone());
-                return Ok(HttpResponse::BadRequest()
-                    .content_type("application/json")
-                    .body(format!(
-                        "msg: err fetching data from index {:?} -  {:?}",
-                        info.index, e
-                    )));
-            }
-        },
-        None => {
-            stats
-                .lock()
-                .unwrap()
-                .increment_http_4xx_errors_counter(info.index.clone());
-            return Ok(HttpResponse::NotFound()
+    match search_by_name(&index_manager, &info.index, &params) {
+        Ok(rs) => {
+            record_search(&stats, &info.index, &rs);
+            Ok(HttpResponse::Ok()
                 .content_type("application/json")
-                .body(format!("msg: index [{:?}] not found", info.index)));
+                .body(rs.to_json().dump()))
+        }
+        Err(e) => {
+            record_error(&stats, &info.index, &e);
+            Ok(index_error_response(&e))
         }
     }
 }
 
+// adds a document, creating the index on first use. it is queued like the
+// Algolia writes and applied by the worker of the index
 #[post("/i/{index}")]
 async fn index_document(
-    req_body: String,
+    body: web::Bytes,
     info: web::Path<DocumentInfo>,
     index_manager: web::Data<Mutex<crate::index_manager::IndexManager>>,
     stats: web::Data<Mutex<crate::stats::SearchStats>>,
 ) -> Result<HttpResponse, Error> {
-    let mut index_manager = index_manager.lock().unwrap();
-    let index = index_manager.index.get(&info.index);
     info!("{}", info.index.clone());
 
     stats
@@ -249,29 +1121,47 @@ async fn index_document(
         .unwrap()
         .increment_index_usage_counter(info.index.clone());
 
-    match index {
-        Some(index_engine) => match index_engine.lock() {
-            Ok(mut ie) => {
-                ie.index_string_document(req_body);
-                return Ok(HttpResponse::Ok()
-                    .content_type("application/json")
-                    .body("msg: Document updated"));
-            }
-            Err(e) => {
-                return Ok(HttpResponse::BadRequest()
-                    .content_type("application/json")
-                    .body(format!("msg: err {:?}", e)))
-            }
-        },
-        None => {
-            index_manager
-                .create_new_index(info.index.clone(), req_body.clone())
-                .unwrap(); // TODO: improve error handling
+    let doc = match parse_body(&body) {
+        Ok(doc) => doc,
+        Err(e) => return Ok(index_error_response(&e)),
+    };
+    if !doc.is_object() {
+        return Ok(index_error_response(&IndexError::InvalidDocument(
+            "document must be a JSON object".to_string(),
+        )));
+    }
 
+    match queue_batch(
+        &index_manager,
+        &info.index,
+        vec![BatchAction::AddObject(doc)],
+    ) {
+        Ok((object_ids, task_id)) => {
+            let rs = object! {
+                objectID: object_ids.into_iter().next(),
+                taskID: task_id,
+                msg: format!("document queued at {}", info.index),
+            };
             Ok(HttpResponse::Ok()
                 .content_type("application/json")
-                .body(format!("document {} indexed at {}", req_body, info.index)))
+                .body(rs.dump()))
         }
+        Err(e) => Ok(index_error_response(&e)),
+    }
+}
+
+// the counters of the whole instance, see stats.rs
+#[get("/stats")]
+async fn search_stats(
+    stats: web::Data<Mutex<crate::stats::SearchStats>>,
+) -> Result<HttpResponse, Error> {
+    match stats.lock().unwrap().dump_json() {
+        Ok(payload) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .body(payload)),
+        Err(e) => Ok(HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .body(e)),
     }
 }
 
@@ -280,36 +1170,103 @@ async fn index_stats(
     info: web::Path<DocumentInfo>,
     data: web::Data<Mutex<crate::index_manager::IndexManager>>,
 ) -> Result<HttpResponse, Error> {
-    let data = data.lock().unwrap();
-    let index = data.index.get(&info.index);
+    // the manager is released before the index is locked
+    let index = data.lock().unwrap().index.get(&info.index).cloned();
 
     match index {
         Some(vect) => match vect.lock() {
             Ok(mut v) => match v.dump_json() {
-                Ok(payload) => {
-                    return Ok(HttpResponse::Ok()
-                        .content_type("application/json")
-                        .body(payload));
-                }
-                Err(e) => {
-                    return Ok(HttpResponse::NoContent()
-                        .content_type("application/json")
-                        .body(e))
-                }
-            },
-            Err(e) => {
-                return Ok(HttpResponse::BadRequest()
+                Ok(payload) => Ok(HttpResponse::Ok()
                     .content_type("application/json")
-                    .body(format!(
-                        "msg: err fetching message from topic {:?} -  {:?}",
-                        info.index, e
-                    )))
-            }
-        },
-        None => {
-            return Ok(HttpResponse::NotFound()
+                    .body(payload)),
+                Err(e) => Ok(HttpResponse::NoContent()
+                    .content_type("application/json")
+                    .body(e)),
+            },
+            Err(e) => Ok(HttpResponse::BadRequest()
                 .content_type("application/json")
-                .body(format!("msg: index [{:?}] not found", info.index)))
-        }
+                .body(format!(
+                    "msg: err fetching message from topic {:?} -  {:?}",
+                    info.index, e
+                ))),
+        },
+        None => Ok(HttpResponse::NotFound()
+            .content_type("application/json")
+            .body(format!("msg: index [{:?}] not found", info.index))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(hits_per_page: u64) -> (String, Option<String>, SearchParams) {
+        let params = SearchParams::from_request(&object! { hitsPerPage: hits_per_page }).unwrap();
+        ("products".to_string(), None, params)
+    }
+
+    // every search finds nb_hits hits
+    fn run(queries: Vec<(String, Option<String>, SearchParams)>, nb_hits: u64) -> Vec<JsonValue> {
+        run_queries(
+            queries,
+            true,
+            |index_name, params| {
+                let mut result = SearchResult::new(params).to_json();
+                result["nbHits"] = nb_hits.into();
+                result["index"] = index_name.into();
+                result["processed"] = true.into();
+                result
+            },
+            |_, _, _| object! { facetHits: [] },
+        )
+    }
+
+    fn processed(results: &[JsonValue]) -> Vec<bool> {
+        results
+            .iter()
+            .map(|result| result["processed"].as_bool().unwrap_or(true))
+            .collect()
+    }
+
+    #[test]
+    fn stop_if_enough_matches_counts_the_hits_of_every_search_run() {
+        let results = run(vec![search(2), search(2), search(2)], 1);
+        assert_eq!(processed(&results), vec![true, true, false]);
+    }
+
+    #[test]
+    fn stop_if_enough_matches_stops_after_a_full_page() {
+        let results = run(vec![search(2), search(2)], 2);
+        assert_eq!(processed(&results), vec![true, false]);
+    }
+
+    #[test]
+    fn stop_if_enough_matches_runs_everything_short_of_a_page() {
+        let results = run(vec![search(5), search(5), search(5)], 1);
+        assert_eq!(processed(&results), vec![true, true, true]);
+    }
+
+    #[test]
+    fn facet_queries_always_run() {
+        let mut facet = search(2);
+        facet.1 = Some("brand".to_string());
+        let results = run(vec![search(2), facet], 2);
+        assert!(results[1]["facetHits"].is_array());
+    }
+
+    #[test]
+    fn every_search_runs_without_the_strategy() {
+        let results = run_queries(
+            vec![search(1), search(1)],
+            false,
+            |_, params| {
+                let mut result = SearchResult::new(params).to_json();
+                result["nbHits"] = 10.into();
+                result["processed"] = true.into();
+                result
+            },
+            |_, _, _| JsonValue::Null,
+        );
+        assert_eq!(processed(&results), vec![true, true]);
     }
 }
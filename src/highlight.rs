@@ -0,0 +1,168 @@
+// _highlightResult / _snippetResult building
+// FTS5 marks the matches, matchLevel and matchedWords are worked out here
+// by comparing the query terms with the tokens of the attribute value
+use json::object;
+use json::JsonValue;
+
+pub const DEFAULT_PRE_TAG: &str = "<em>";
+pub const DEFAULT_POST_TAG: &str = "</em>";
+pub const DEFAULT_ELLIPSIS: &str = "…";
+// words per snippet when attributesToSnippet doesn't say, and the FTS5 limit
+pub const DEFAULT_SNIPPET_WORDS: usize = 10;
+pub const MAX_SNIPPET_WORDS: usize = 64;
+
+// lowercased alphanumeric runs, close enough to the FTS5 unicode61 tokenizer
+pub fn tokens(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+        .collect()
+}
+
+// query terms found in a value. a term matches when all of its tokens do
+pub fn matched_words(value: &str, terms: &[String]) -> Vec<String> {
+    let value_tokens = tokens(value);
+    terms
+        .iter()
+        .filter(|term| {
+            let term_tokens = tokens(term);
+            !term_tokens.is_empty() && term_tokens.iter().all(|t| value_tokens.contains(t))
+        })
+        .cloned()
+        .collect()
+}
+
+pub fn match_level(matched: usize, terms: usize) -> &'static str {
+    if matched == 0 {
+        "none"
+    } else if matched == terms {
+        "full"
+    } else {
+        "partial"
+    }
+}
+
+// one attribute entry of _highlightResult. `marked` is the FTS5 output,
+// None when there was nothing to match against
+pub fn highlight_result(value: &str, marked: Option<String>, terms: &[String]) -> JsonValue {
+    let matched = matched_words(value, terms);
+    // every token of the value is a query token
+    let query_tokens: Vec<String> = terms.iter().flat_map(|term| tokens(term)).collect();
+    let fully_highlighted =
+        !matched.is_empty() && tokens(value).iter().all(|t| query_tokens.contains(t));
+
+    object! {
+        value: marked.unwrap_or_else(|| value.to_string()),
+        matchLevel: match_level(matched.len(), terms.len()),
+        fullyHighlighted: fully_highlighted,
+        matchedWords: matched,
+    }
+}
+
+// one attribute entry of _snippetResult. without FTS5 output (no query)
+// the snippet is the start of the value
+pub fn snippet_result(
+    value: &str,
+    marked: Option<String>,
+    terms: &[String],
+    words: usize,
+    ellipsis: &str,
+) -> JsonValue {
+    let matched = matched_words(value, terms);
+    let snippet = match marked {
+        Some(marked) => marked,
+        None => leading_words(value, words, ellipsis),
+    };
+
+    object! {
+        value: snippet,
+        matchLevel: match_level(matched.len(), terms.len()),
+    }
+}
+
+// the snippet of an array element, cut from its highlighted text around
+// its first match
+pub fn crop(marked: &str, words: usize, ellipsis: &str, pre_tag: &str) -> String {
+    let all_words: Vec<&str> = marked.split_whitespace().collect();
+    if all_words.len() <= words {
+        return marked.to_string();
+    }
+    let first_match = all_words
+        .iter()
+        .position(|word| word.contains(pre_tag))
+        .unwrap_or(0);
+    let start = first_match
+        .saturating_sub(words / 2)
+        .min(all_words.len() - words);
+    let end = start + words;
+    format!(
+        "{}{}{}",
+        if start > 0 { ellipsis } else { "" },
+        all_words[start..end].join(" "),
+        if end < all_words.len() { ellipsis } else { "" }
+    )
+}
+
+fn leading_words(value: &str, words: usize, ellipsis: &str) -> String {
+    let all_words: Vec<&str> = value.split_whitespace().collect();
+    if all_words.len() <= words {
+        return value.to_string();
+    }
+    format!("{}{}", all_words[..words].join(" "), ellipsis)
+}
+
+// a facet value matches a facet query when every query token starts a word
+// of the value. returns the value with those word starts marked
+pub fn highlight_facet_value(
+    value: &str,
+    facet_query: &str,
+    pre_tag: &str,
+    post_tag: &str,
+) -> Option<String> {
+    let query_tokens = tokens(facet_query);
+    let mut matched = vec![false; query_tokens.len()];
+    let chars: Vec<char> = value.chars().collect();
+    let mut out = String::new();
+
+    let mut i = 0;
+    while i < chars.len() {
+        if !chars[i].is_alphanumeric() {
+            out.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < chars.len() && chars[i].is_alphanumeric() {
+            i += 1;
+        }
+        let word = &chars[start..i];
+
+        // the longest query token this word starts with
+        let mut prefix = 0;
+        for (k, token) in query_tokens.iter().enumerate() {
+            let token: Vec<char> = token.chars().collect();
+            let starts_word = token.len() <= word.len()
+                && token
+                    .iter()
+                    .zip(word)
+                    .all(|(t, w)| w.to_lowercase().eq(t.to_lowercase()));
+            if starts_word {
+                matched[k] = true;
+                prefix = prefix.max(token.len());
+            }
+        }
+
+        if prefix > 0 {
+            out.push_str(pre_tag);
+            out.extend(&word[..prefix]);
+            out.push_str(post_tag);
+        }
+        out.extend(&word[prefix..]);
+    }
+
+    if matched.iter().all(|m| *m) {
+        Some(out)
+    } else {
+        None
+    }
+}
@@ -1,26 +1,208 @@
 // index interface
-use chrono::Local;
-use json::array;
+use crate::attributes;
+use crate::batch::BatchAction;
+use crate::facets;
+use crate::filters::{Filter, TAGS};
+use crate::highlight;
+use crate::search_params::SearchParams;
+use crate::settings::{self, RankingCriterion};
+use crate::synonyms::{self, Synonym};
+use crate::tasks::{self, Task};
+use chrono::{Local, TimeZone, Utc};
 use json::object;
 use json::JsonValue;
-use serde::{Deserialize, Serialize};
+use sqlite::State;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 use uuid::Uuid;
 
+// index metadata lives next to the documents, one key/value row per field
+const METADATA_TABLE: &str = "_morocco_meta";
+// maps Algolia objectIDs to the rowid of the document in the FTS5 table,
+// with the original JSON document
+const OBJECTS_TABLE: &str = "_morocco_objects";
+// facet values of the attributesForFaceting attributes and _tags, see facets.rs
+pub const FACETS_TABLE: &str = "_morocco_facets";
+// typed values of every attribute, see attributes.rs
+pub const ATTRIBUTES_TABLE: &str = "_morocco_attributes";
+// queued writes, see tasks.rs
+const TASKS_TABLE: &str = "_morocco_tasks";
+// synonym sets by objectID, see synonyms.rs
+const SYNONYMS_TABLE: &str = "_morocco_synonyms";
+const OBJECT_ID: &str = "objectID";
+// the FTS5 table being built when the schema gets new attributes
+const NEXT_GENERATION_TABLE: &str = "_morocco_next_generation";
+// bm25() arguments after the table, SQLite allows 127 in all
+const MAX_BM25_WEIGHTS: usize = 126;
+
 pub struct IndexEngine {
     path: PathBuf,
     name: String,
     version: Uuid,
     db_connection: sqlite::Connection,
     created_at: i64,
+    updated_at: i64,
     attribute_list: Vec<String>,
+    settings: JsonValue,
+    // the synonyms table, in objectID order
+    synonyms: Vec<Synonym>,
+    document_count: u64,
+    last_task_id: u64,
+    // seconds the last applied task took
+    last_build_time_s: u64,
+    // set once the index is deleted or replaced, no more tasks run
+    closed: bool,
+    // records removed by deleteByQuery tasks, until the worker reports them
+    deleted_by_query: u64,
+}
+
+#[derive(Debug)]
+pub enum IndexError {
+    InvalidDocument(String),
+    InvalidAttribute(String),
+    InvalidParameter(String),
+    NotFound(String),
+    Storage(String),
+    // a failed request inside a batch, by its position in the payload
+    Batch {
+        position: usize,
+        error: Box<IndexError>,
+    },
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexError::InvalidDocument(e) => write!(f, "invalid document: {}", e),
+            IndexError::InvalidAttribute(a) => write!(f, "invalid attribute name: {:?}", a),
+            IndexError::InvalidParameter(e) => write!(f, "{}", e),
+            IndexError::NotFound(e) => write!(f, "{}", e),
+            IndexError::Storage(e) => write!(f, "storage error: {}", e),
+            IndexError::Batch { position, error } => {
+                write!(f, "requests[{}]: {}", position, error)
+            }
+        }
+    }
+}
+
+impl From<sqlite::Error> for IndexError {
+    fn from(e: sqlite::Error) -> Self {
+        IndexError::Storage(e.to_string())
+    }
+}
+
+impl IndexError {
+    pub fn status(&self) -> u16 {
+        match self {
+            IndexError::NotFound(_) => 404,
+            IndexError::Storage(_) => 500,
+            IndexError::Batch { error, .. } => error.status(),
+            _ => 400,
+        }
+    }
+
+    // Algolia-style error payload
+    pub fn to_json(&self) -> JsonValue {
+        object! {
+            message: self.to_string(),
+            status: self.status(),
+        }
+    }
+}
+
+pub struct SearchResult {
+    pub hits: Vec<JsonValue>,
+    pub nb_hits: u64,
+    pub page: u64,
+    pub hits_per_page: u64,
+    pub offset: Option<u64>,
+    pub length: Option<u64>,
+    pub processing_time_ms: u128,
+    pub query: String,
+    pub params: String,
+    // value -> count per attribute, when facets were asked for
+    pub facets: Option<JsonValue>,
+    pub facets_stats: Option<JsonValue>,
+}
+
+// the documents a search is about
+struct Matching {
+    from: String,
+    binds: Vec<(String, sqlite::Value)>,
+    // whether there is a MATCH expression, FTS5 functions need one
+    full_text: bool,
+    // whether `from` ends with a WHERE clause
+    filtered: bool,
+}
+
+impl Matching {
+    // the FROM and WHERE with one more condition
+    fn and_where(&self, condition: &str) -> String {
+        let keyword = if self.filtered { "AND" } else { "WHERE" };
+        format!("{} {} {}", self.from, keyword, condition)
+    }
 }
-#[derive(Serialize, Deserialize)]
-struct Resultset {
-    count: i64,
-    rows: Vec<HashMap<String, String>>,
-    attributes: HashMap<String, String>,
+
+impl SearchResult {
+    // an empty result for the given parameters
+    pub fn new(params: &SearchParams) -> Self {
+        SearchResult {
+            hits: Vec::new(),
+            nb_hits: 0,
+            page: params.page,
+            hits_per_page: params.hits_per_page,
+            offset: params.offset,
+            length: params.offset.map(|_| params.offset_length().1),
+            processing_time_ms: 0,
+            query: params.query.clone(),
+            params: params.to_query_string(),
+            facets: if params.facets.is_empty() {
+                None
+            } else {
+                Some(JsonValue::new_object())
+            },
+            facets_stats: None,
+        }
+    }
+
+    pub fn nb_pages(&self) -> u64 {
+        if self.hits_per_page == 0 {
+            return 0;
+        }
+        self.nb_hits.div_ceil(self.hits_per_page)
+    }
+
+    // the response body algoliasearch expects from a query
+    pub fn to_json(&self) -> JsonValue {
+        let mut out = object! {
+            hits: self.hits.clone(),
+            nbHits: self.nb_hits,
+            page: self.page,
+            nbPages: self.nb_pages(),
+            hitsPerPage: self.hits_per_page,
+            exhaustiveNbHits: true,
+            processingTimeMS: self.processing_time_ms as u64,
+            query: self.query.clone(),
+            params: self.params.clone(),
+        };
+        if let Some(offset) = self.offset {
+            out["offset"] = offset.into();
+        }
+        if let Some(length) = self.length {
+            out["length"] = length.into();
+        }
+        if let Some(facets) = &self.facets {
+            out["facets"] = facets.clone();
+            out["exhaustiveFacetsCount"] = true.into();
+        }
+        if let Some(facets_stats) = self.facets_stats.as_ref().filter(|s| !s.is_empty()) {
+            out["facets_stats"] = facets_stats.clone();
+        }
+        out
+    }
 }
 
 impl IndexEngine {
@@ -30,134 +212,2040 @@ impl IndexEngine {
             name: self.name.clone(),
             version: self.version.clone().to_string(),
             created_at: self.created_at,
+            updated_at: self.updated_at,
             schema: self.attribute_list.clone(),
+            settings: self.settings.clone(),
+            document_count: self.document_count,
         };
         Ok(out.dump())
     }
 
-    pub fn load_or_create_index(path: PathBuf, name: String) -> Self {
+    pub fn load_or_create_index(path: PathBuf, name: String) -> Result<Self, IndexError> {
         let mut path = path;
 
         if !path.is_file() {
             path.push(format!("{}.db", name));
         }
 
-        IndexEngine {
+        let now = Local::now().timestamp_millis();
+        let mut ie = IndexEngine {
             path: path.clone(),
             name,
             version: Uuid::new_v4(),
-            db_connection: sqlite::open(path.clone()).unwrap(),
-            created_at: Local::now().timestamp_millis(),
+            db_connection: sqlite::open(path)?,
+            created_at: now,
+            updated_at: now,
             attribute_list: Vec::new(),
-        }
+            settings: JsonValue::new_object(),
+            synonyms: Vec::new(),
+            document_count: 0,
+            last_task_id: 0,
+            last_build_time_s: 0,
+            closed: false,
+            deleted_by_query: 0,
+        };
+        ie.load_metadata()?;
+        ie.load_objects()?;
+        ie.load_values()?;
+        ie.load_tasks()?;
+        ie.load_synonyms()?;
+
+        Ok(ie)
     } // new index engine
 
-    pub fn new(path: PathBuf, name: String, doc: String) -> Self {
-        let mut ie = IndexEngine::load_or_create_index(path, name);
-        ie.create_schema_from_string(doc);
+    // reads the metadata table, creating it for new indexes and for
+    // databases written before metadata was persisted
+    fn load_metadata(&mut self) -> Result<(), IndexError> {
+        self.execute(format!(
+            "CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY, value TEXT NOT NULL)",
+            METADATA_TABLE
+        ))?;
 
-        ie
-    } // new index engine
+        let mut metadata: HashMap<String, String> = HashMap::new();
+        let mut statement = self
+            .db_connection
+            .prepare(format!("SELECT key, value FROM {}", METADATA_TABLE))?;
+        while let State::Row = statement.next()? {
+            let key: String = statement.read(0)?;
+            let value: String = statement.read(1)?;
+            metadata.insert(key, value);
+        }
+        drop(statement);
+
+        if metadata.is_empty() {
+            if self.table_exists(&self.name)? {
+                self.attribute_list = self.table_columns(&self.name)?;
+                self.document_count = self.count_rows(&self.name)?;
+            }
+            return self.save_metadata();
+        }
+
+        if let Some(v) = metadata
+            .get("version")
+            .and_then(|v| Uuid::parse_str(v).ok())
+        {
+            self.version = v;
+        }
+        if let Some(v) = metadata.get("created_at").and_then(|v| v.parse().ok()) {
+            self.created_at = v;
+        }
+        if let Some(v) = metadata.get("updated_at").and_then(|v| v.parse().ok()) {
+            self.updated_at = v;
+        }
+        if let Some(v) = metadata.get("document_count").and_then(|v| v.parse().ok()) {
+            self.document_count = v;
+        }
+        if let Some(v) = metadata.get("last_task_id").and_then(|v| v.parse().ok()) {
+            self.last_task_id = v;
+        }
+        if let Some(v) = metadata
+            .get("last_build_time_s")
+            .and_then(|v| v.parse().ok())
+        {
+            self.last_build_time_s = v;
+        }
+        if let Some(v) = metadata.get("schema").and_then(|v| json::parse(v).ok()) {
+            self.attribute_list = v.members().map(|a| a.to_string()).collect();
+        }
+        if let Some(v) = metadata.get("settings").and_then(|v| json::parse(v).ok()) {
+            self.settings = v;
+        }
+
+        Ok(())
+    }
+
+    // creates the objectID table. documents indexed before objectIDs were
+    // tracked get a generated one so they can be addressed like any other,
+    // documents indexed before they were kept get one rebuilt from their text
+    fn load_objects(&mut self) -> Result<(), IndexError> {
+        if !self.table_exists(OBJECTS_TABLE)? {
+            self.execute(format!(
+                "CREATE TABLE {} (id INTEGER PRIMARY KEY, object_id TEXT NOT NULL UNIQUE, document TEXT)",
+                OBJECTS_TABLE
+            ))?;
+            if self.table_exists(&self.name)? {
+                self.execute(format!(
+                    "INSERT INTO {} (id, object_id) SELECT rowid, lower(hex(randomblob(16))) FROM {}",
+                    OBJECTS_TABLE,
+                    quote_identifier(&self.name)
+                ))?;
+            }
+        } else if !self
+            .table_columns(OBJECTS_TABLE)?
+            .contains(&"document".to_string())
+        {
+            self.execute(format!(
+                "ALTER TABLE {} ADD COLUMN document TEXT",
+                OBJECTS_TABLE
+            ))?;
+        }
+
+        if !self.table_exists(&self.name)? {
+            return Ok(());
+        }
+        let mut documents = vec![];
+        let mut statement = self.db_connection.prepare(format!(
+            "SELECT o.id, o.object_id, t.* FROM {} o JOIN {} t ON t.rowid = o.id WHERE o.document IS NULL",
+            OBJECTS_TABLE,
+            quote_identifier(&self.name)
+        ))?;
+        while let State::Row = statement.next()? {
+            let mut doc = JsonValue::new_object();
+            for (i, attribute) in self.attribute_list.iter().enumerate() {
+                // non-string values were indexed as their JSON text
+                if let Some(text) = statement.read::<Option<String>>(i + 2)? {
+                    doc[attribute.as_str()] = match json::parse(&text) {
+                        Ok(value) if !value.is_string() && !value.is_null() => value,
+                        _ => text.into(),
+                    };
+                }
+            }
+            doc[OBJECT_ID] = statement.read::<String>(1)?.into();
+            documents.push((statement.read::<i64>(0)?, doc.dump()));
+        }
+        drop(statement);
+
+        for (id, document) in documents {
+            let mut statement = self
+                .db_connection
+                .prepare(format!(
+                    "UPDATE {} SET document = ? WHERE id = ?",
+                    OBJECTS_TABLE
+                ))?
+                .bind(1, document.as_str())?
+                .bind(2, id)?;
+            statement.next()?;
+        }
+        Ok(())
+    }
+
+    // creates the facets and typed attributes tables. databases written
+    // before they existed get them filled from the stored documents
+    fn load_values(&mut self) -> Result<(), IndexError> {
+        if self.table_exists(FACETS_TABLE)? && self.table_exists(ATTRIBUTES_TABLE)? {
+            return Ok(());
+        }
+        self.execute(format!(
+            "CREATE TABLE IF NOT EXISTS {} (id INTEGER NOT NULL, attribute TEXT NOT NULL, value TEXT NOT NULL, number REAL)",
+            FACETS_TABLE
+        ))?;
+        // value has no declared type: SQLite keeps integers, reals and text as given
+        self.execute(format!(
+            "CREATE TABLE IF NOT EXISTS {} (id INTEGER NOT NULL, attribute TEXT NOT NULL, type TEXT NOT NULL, value)",
+            ATTRIBUTES_TABLE
+        ))?;
+        self.execute(format!(
+            "CREATE INDEX IF NOT EXISTS {}_attribute ON {} (attribute, value)",
+            FACETS_TABLE, FACETS_TABLE
+        ))?;
+        self.execute(format!(
+            "CREATE INDEX IF NOT EXISTS {}_attribute ON {} (attribute, value)",
+            ATTRIBUTES_TABLE, ATTRIBUTES_TABLE
+        ))?;
+        for side_table in [FACETS_TABLE, ATTRIBUTES_TABLE] {
+            self.execute(format!(
+                "CREATE INDEX IF NOT EXISTS {}_id ON {} (id)",
+                side_table, side_table
+            ))?;
+        }
+        self.rebuild_values()
+    }
+
+    // creates the tasks table. taskIDs are never reused: databases written
+    // before tasks were queued number them after the last one they counted
+    fn load_tasks(&mut self) -> Result<(), IndexError> {
+        if self.table_exists(TASKS_TABLE)? {
+            return Ok(());
+        }
+        self.execute(format!(
+            "CREATE TABLE {} (id INTEGER PRIMARY KEY AUTOINCREMENT, kind TEXT NOT NULL, payload TEXT NOT NULL, status TEXT NOT NULL, error TEXT, created_at INTEGER NOT NULL)",
+            TASKS_TABLE
+        ))?;
+        if self.last_task_id > 0 {
+            let mut statement = self
+                .db_connection
+                .prepare("INSERT INTO sqlite_sequence (name, seq) VALUES (?, ?)")?
+                .bind(1, TASKS_TABLE)?
+                .bind(2, self.last_task_id as i64)?;
+            statement.next()?;
+        }
+        Ok(())
+    }
+
+    // creates the synonyms table and reads the synonym sets
+    fn load_synonyms(&mut self) -> Result<(), IndexError> {
+        self.execute(format!(
+            "CREATE TABLE IF NOT EXISTS {} (object_id TEXT PRIMARY KEY, synonym TEXT NOT NULL)",
+            SYNONYMS_TABLE
+        ))?;
+        self.synonyms.clear();
+        let mut statement = self.db_connection.prepare(format!(
+            "SELECT synonym FROM {} ORDER BY object_id",
+            SYNONYMS_TABLE
+        ))?;
+        while let State::Row = statement.next()? {
+            let synonym = json::parse(&statement.read::<String>(0)?)
+                .map_err(|e| IndexError::Storage(e.to_string()))?;
+            self.synonyms.push(Synonym::from_json(&synonym)?);
+        }
+        Ok(())
+    }
+
+    fn save_metadata(&mut self) -> Result<(), IndexError> {
+        let entries = vec![
+            ("name", self.name.clone()),
+            ("version", self.version.to_string()),
+            ("created_at", self.created_at.to_string()),
+            ("updated_at", self.updated_at.to_string()),
+            (
+                "schema",
+                JsonValue::from(self.attribute_list.clone()).dump(),
+            ),
+            ("settings", self.settings.dump()),
+            ("document_count", self.document_count.to_string()),
+            ("last_task_id", self.last_task_id.to_string()),
+            ("last_build_time_s", self.last_build_time_s.to_string()),
+        ];
+
+        for (key, value) in entries {
+            let mut statement = self
+                .db_connection
+                .prepare(format!(
+                    "INSERT OR REPLACE INTO {} (key, value) VALUES (?, ?)",
+                    METADATA_TABLE
+                ))
+                .and_then(|s| s.bind(1, key))
+                .and_then(|s| s.bind(2, value.as_str()))?;
+            statement.next()?;
+        }
+
+        Ok(())
+    }
+
+    // runs a write inside a transaction and persists the metadata with it.
+    // on failure the in-memory metadata and synonyms are reloaded from the
+    // rolled back database
+    fn write_transaction<T, F>(&mut self, write: F) -> Result<T, IndexError>
+    where
+        F: FnOnce(&mut Self) -> Result<T, IndexError>,
+    {
+        self.execute("BEGIN")?;
+        let result = write(self).and_then(|v| {
+            self.updated_at = Local::now().timestamp_millis();
+            self.save_metadata()?;
+            self.execute("COMMIT")?;
+            Ok(v)
+        });
+
+        if result.is_err() {
+            if let Err(e) = self.execute("ROLLBACK") {
+                info!("rollback failed: {}", e);
+            }
+            self.load_metadata()?;
+            self.load_synonyms()?;
+        }
+        result
+    }
+
+    fn execute<T: AsRef<str>>(&self, statement: T) -> Result<(), IndexError> {
+        self.db_connection
+            .execute(statement)
+            .map_err(|e| IndexError::Storage(e.to_string()))
+    }
 
-    pub fn search(&mut self, qs: String) -> Result<String, serde_json::Error> {
-        let query = format!(
-            "SELECT * FROM {} WHERE {} MATCH \"{}\"",
-            self.name,
-            self.name,
-            // self.attribute_list.join(","),
-            qs
+    fn table_exists(&self, table: &str) -> Result<bool, IndexError> {
+        let mut statement = self
+            .db_connection
+            .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?")
+            .and_then(|s| s.bind(1, table))?;
+        match statement.next() {
+            Ok(state) => Ok(state == State::Row),
+            Err(e) => Err(IndexError::Storage(e.to_string())),
+        }
+    }
+
+    fn table_columns(&self, table: &str) -> Result<Vec<String>, IndexError> {
+        let mut columns = vec![];
+        let mut statement = self
+            .db_connection
+            .prepare(format!("PRAGMA table_info({})", quote_identifier(table)))?;
+        while let State::Row = statement.next()? {
+            columns.push(statement.read::<String>(1)?);
+        }
+        Ok(columns)
+    }
+
+    fn count_rows(&self, table: &str) -> Result<u64, IndexError> {
+        let mut statement = self
+            .db_connection
+            .prepare(format!("SELECT count(*) FROM {}", quote_identifier(table)))?;
+        statement.next()?;
+        statement
+            .read::<i64>(0)
+            .map(|count| count as u64)
+            .map_err(|e| IndexError::Storage(e.to_string()))
+    }
+
+    // the FROM and WHERE of the queries of a search, with the values to bind.
+    // None when nothing can match
+    fn matching(&self, params: &SearchParams) -> Result<Option<Matching>, IndexError> {
+        // synonyms turn query words into groups of alternatives
+        let terms = crate::fts_query::terms(&params.query);
+        let synonyms: &[Synonym] = if params.synonyms { &self.synonyms } else { &[] };
+        let mut expression = crate::fts_query::expression(&synonyms::expand(&terms, synonyms));
+
+        // searchableAttributes restricts the columns the query can match
+        let searchable: Vec<String> = settings::searchable_attributes(&self.settings)
+            .into_iter()
+            .flatten()
+            .collect();
+        if let (Some(e), false) = (&expression, searchable.is_empty()) {
+            let columns: Vec<&String> = self
+                .attribute_list
+                .iter()
+                .filter(|attribute| {
+                    searchable
+                        .iter()
+                        .any(|name| attributes::covers(name, attribute))
+                })
+                .collect();
+            if columns.is_empty() {
+                return Ok(None);
+            }
+            expression = Some(crate::fts_query::restrict_columns(e, &columns));
+        }
+
+        // no document was ever indexed, there is no table to search
+        if self.attribute_list.is_empty() {
+            return Ok(None);
+        }
+
+        let table = quote_identifier(&self.name);
+        // the MATCH expression and filter values are always bound, never
+        // formatted into the statement
+        let mut from = format!(
+            "FROM {} JOIN {} o ON o.id = {}.rowid",
+            table, OBJECTS_TABLE, table
         );
+        let mut conditions = vec![];
+        let mut binds: Vec<(String, sqlite::Value)> = vec![];
+        if let Some(expression) = &expression {
+            debug!("search expression: {}", expression);
+            conditions.push(format!("{} MATCH :expression", table));
+            binds.push((
+                ":expression".to_string(),
+                sqlite::Value::String(expression.clone()),
+            ));
+        }
+        if let Some(filter) = params.filter() {
+            validate_filter(&self.settings, &filter)?;
+            conditions.push(filter.to_sql(&format!("{}.rowid", table), &mut binds));
+        }
+        if !conditions.is_empty() {
+            from.push_str(&format!(" WHERE {}", conditions.join(" AND ")));
+        }
 
-        debug!("search query: {}", query);
-        let mut rs = Resultset {
-            count: 0,
-            rows: Vec::new(),
-            attributes: HashMap::new(),
+        Ok(Some(Matching {
+            from,
+            binds,
+            full_text: expression.is_some(),
+            filtered: !conditions.is_empty(),
+        }))
+    }
+
+    // one page of hits matching the query plus the total number of matches
+    pub fn search(&mut self, params: &SearchParams) -> Result<SearchResult, IndexError> {
+        let started = Instant::now();
+        let params = &params.with_settings(&self.settings);
+        let mut rs = SearchResult::new(params);
+        let matching = match self.matching(params)? {
+            Some(matching) => matching,
+            None => {
+                rs.processing_time_ms = started.elapsed().as_millis();
+                return Ok(rs);
+            }
         };
+        let (from, binds, full_text) = (&matching.from, &matching.binds, matching.full_text);
+        let table = quote_identifier(&self.name);
 
-        self.db_connection
-            .iterate(query, |pairs| {
-                let mut new_pairs: HashMap<String, String> = HashMap::new();
-                for &(column, value) in pairs.iter() {
-                    debug!("result: {}:{:?}", column, value);
-                    new_pairs.insert(column.to_string(), value.unwrap().to_string());
-
-                    // rs.attributes
-                    //     .insert(column.to_string(), value.unwrap().to_string());
+        let mut statement = bind_all(
+            self.db_connection
+                .prepare(format!("SELECT count(*) {}", from))?,
+            binds,
+        )?;
+        statement.next()?;
+        rs.nb_hits = statement.read::<i64>(0)? as u64;
+        drop(statement);
+
+        if !params.facets.is_empty() {
+            let (facets, facets_stats) = self.facet_counts(from, binds, params)?;
+            rs.facets = Some(facets);
+            rs.facets_stats = Some(facets_stats);
+        }
+
+        // FTS5 can only mark matches when there is a MATCH expression
+        let highlighted: Vec<(usize, &String)> = self
+            .attribute_list
+            .iter()
+            .enumerate()
+            .filter(|(_, attribute)| params.highlights(attribute))
+            .collect();
+        let snippeted: Vec<(usize, &String, usize)> = self
+            .attribute_list
+            .iter()
+            .enumerate()
+            .filter_map(|(column, attribute)| {
+                params
+                    .snippet_words(attribute)
+                    .map(|words| (column, attribute, words))
+            })
+            .collect();
+        let mut columns = format!("o.object_id AS objectID, o.document, {}.*", table);
+        if full_text {
+            for (column, _) in &highlighted {
+                columns.push_str(&format!(
+                    ", highlight({}, {}, :pre_tag, :post_tag)",
+                    table, column
+                ));
+            }
+            for (column, _, words) in &snippeted {
+                columns.push_str(&format!(
+                    ", snippet({}, {}, :pre_tag, :post_tag, :ellipsis, {})",
+                    table, column, words
+                ));
+            }
+            // arrays are snippeted element by element, from their highlights
+            for (column, _, _) in &snippeted {
+                columns.push_str(&format!(
+                    ", highlight({}, {}, :pre_tag, :post_tag)",
+                    table, column
+                ));
+            }
+        }
+
+        // best matches first: bm25() is lower for better matches. without a
+        // query there is nothing to rank, only the custom criteria apply.
+        // ties are broken by insertion order
+        let mut order = vec![];
+        let mut page_binds = binds.clone();
+        let score = self.score(&table);
+        if full_text {
+            columns.push_str(&format!(", {}", score));
+        }
+        for criterion in settings::ranking_criteria(&self.settings) {
+            match criterion {
+                RankingCriterion::Relevance if full_text => order.push(score.clone()),
+                RankingCriterion::Relevance => (),
+                RankingCriterion::Asc(attribute) => {
+                    order.extend(custom_order(&table, &attribute, "ASC", &mut page_binds))
+                }
+                RankingCriterion::Desc(attribute) => {
+                    order.extend(custom_order(&table, &attribute, "DESC", &mut page_binds))
                 }
-                rs.count += 1;
-                rs.rows.push(new_pairs);
+            }
+        }
+        order.push(format!("{}.rowid", table));
+
+        let (offset, length) = params.offset_length();
+        let mut statement = self
+            .db_connection
+            .prepare(format!(
+                "SELECT {} {} ORDER BY {} LIMIT :limit OFFSET :offset",
+                columns,
+                from,
+                order.join(", ")
+            ))?
+            .bind_by_name(":limit", i64::try_from(length).unwrap_or(i64::MAX))?
+            .bind_by_name(":offset", i64::try_from(offset).unwrap_or(i64::MAX))?;
+        statement = bind_all(statement, &page_binds)?;
+        if full_text {
+            if !highlighted.is_empty() || !snippeted.is_empty() {
+                statement = statement
+                    .bind_by_name(":pre_tag", params.highlight_pre_tag.as_str())?
+                    .bind_by_name(":post_tag", params.highlight_post_tag.as_str())?;
+            }
+            if !snippeted.is_empty() {
+                statement =
+                    statement.bind_by_name(":ellipsis", params.snippet_ellipsis_text.as_str())?;
+            }
+        }
+
+        let terms = crate::fts_query::terms(&params.query);
+        // objectID, the document and the indexed text of the attributes come
+        // first, then highlights, snippets, snippeted highlights and score
+        let text_from = 2;
+        let marks_from = text_from + self.attribute_list.len();
+        let snippets_from = marks_from + highlighted.len();
+        let score_column = snippets_from + 2 * snippeted.len();
+        let unretrievable = settings::list(&self.settings, "unretrievableAttributes");
+        let mut position = offset;
+        while let State::Row = statement.next()? {
+            let document = statement.read::<Option<String>>(1)?.unwrap_or_default();
+            let mut hit = json::parse(&document).map_err(|e| IndexError::Storage(e.to_string()))?;
+            hit[OBJECT_ID] = statement.read::<String>(0)?.into();
+            hit = remove_attributes(hit, &unretrievable);
+            debug!("result: {}", hit);
+            // only attributes the hit shows get highlighted
+            let values = attributes::flatten(&hit);
+            let is_array = |attribute: &str| {
+                values
+                    .iter()
+                    .find(|(path, _)| path == attribute)
+                    .map(|(_, value)| value.is_array())
+            };
+
+            // results of nested attributes nest like the document does,
+            // arrays get one result per element
+            let mut highlight_result = JsonValue::new_object();
+            for (i, (column, attribute)) in highlighted.iter().enumerate() {
+                let (text, array) = match (
+                    read_text(&statement, text_from + column)?,
+                    is_array(attribute),
+                ) {
+                    (Some(text), Some(array)) => (text, array),
+                    _ => continue,
+                };
+                let marked = if full_text {
+                    read_text(&statement, marks_from + i)?
+                } else {
+                    None
+                };
+                let result = if array {
+                    array_elements(&text, marked)
+                        .into_iter()
+                        .map(|(element, marked)| {
+                            highlight::highlight_result(&element, marked, &terms)
+                        })
+                        .collect::<Vec<JsonValue>>()
+                        .into()
+                } else {
+                    highlight::highlight_result(&text, marked, &terms)
+                };
+                attributes::set_path(&mut highlight_result, attribute, result);
+            }
+
+            let mut snippet_result = JsonValue::new_object();
+            for (i, (column, attribute, words)) in snippeted.iter().enumerate() {
+                let (text, array) = match (
+                    read_text(&statement, text_from + column)?,
+                    is_array(attribute),
+                ) {
+                    (Some(text), Some(array)) => (text, array),
+                    _ => continue,
+                };
+                let ellipsis = &params.snippet_ellipsis_text;
+                let result = if array {
+                    let marked = if full_text {
+                        read_text(&statement, snippets_from + snippeted.len() + i)?
+                    } else {
+                        None
+                    };
+                    array_elements(&text, marked)
+                        .into_iter()
+                        .map(|(element, marked)| {
+                            let cropped = marked.map(|marked| {
+                                highlight::crop(
+                                    &marked,
+                                    *words,
+                                    ellipsis,
+                                    &params.highlight_pre_tag,
+                                )
+                            });
+                            highlight::snippet_result(&element, cropped, &terms, *words, ellipsis)
+                        })
+                        .collect::<Vec<JsonValue>>()
+                        .into()
+                } else {
+                    let marked = if full_text {
+                        read_text(&statement, snippets_from + i)?
+                    } else {
+                        None
+                    };
+                    highlight::snippet_result(&text, marked, &terms, *words, ellipsis)
+                };
+                attributes::set_path(&mut snippet_result, attribute, result);
+            }
+
+            let ranking_info = if params.get_ranking_info {
+                let score = if full_text {
+                    statement.read::<f64>(score_column)?
+                } else {
+                    0.0
+                };
+                Some(ranking_info(&hit, &terms, score, rs.nb_hits - position))
+            } else {
+                None
+            };
+
+            hit = retrieve_attributes(hit, params.attributes_to_retrieve.as_deref());
+            hit["_highlightResult"] = highlight_result;
+            if !snippeted.is_empty() {
+                hit["_snippetResult"] = snippet_result;
+            }
+            if let Some(ranking_info) = ranking_info {
+                hit["_rankingInfo"] = ranking_info;
+            }
+            rs.hits.push(hit);
+            position += 1;
+        }
+
+        rs.processing_time_ms = started.elapsed().as_millis();
+        Ok(rs)
+    }
+
+    // the objectIDs of every document matching the query and filters
+    pub fn matching_object_ids(&self, params: &SearchParams) -> Result<Vec<String>, IndexError> {
+        let params = &params.with_settings(&self.settings);
+        let matching = match self.matching(params)? {
+            Some(matching) => matching,
+            None => return Ok(vec![]),
+        };
+        let mut statement = bind_all(
+            self.db_connection.prepare(format!(
+                "SELECT o.object_id {} ORDER BY o.id",
+                matching.from
+            ))?,
+            &matching.binds,
+        )?;
+        let mut object_ids = vec![];
+        while let State::Row = statement.next()? {
+            object_ids.push(statement.read::<String>(0)?);
+        }
+        Ok(object_ids)
+    }
+
+    // one batch of documents matching the query and filters, in rowid order
+    // after the `after` rowid. returns the last rowid of the batch when
+    // more documents follow, see browse.rs
+    pub fn browse(
+        &self,
+        params: &SearchParams,
+        after: i64,
+    ) -> Result<(SearchResult, Option<i64>), IndexError> {
+        let started = Instant::now();
+        let params = &params.with_settings(&self.settings);
+        let mut rs = SearchResult::new(params);
+        let matching = match self.matching(params)? {
+            Some(matching) => matching,
+            None => {
+                rs.processing_time_ms = started.elapsed().as_millis();
+                return Ok((rs, None));
+            }
+        };
 
-                true
+        // counting every match is as long as browsing them all, only the
+        // first batch has nbHits
+        if after == 0 {
+            let mut statement = bind_all(
+                self.db_connection
+                    .prepare(format!("SELECT count(*) {}", matching.from))?,
+                &matching.binds,
+            )?;
+            statement.next()?;
+            rs.nb_hits = statement.read::<i64>(0)? as u64;
+        }
+
+        // the batch starts right after the cursor instead of skipping the
+        // rows before it. FTS5 walks its matches in rowid order, without a
+        // MATCH the objects table does
+        let rowid = if matching.full_text {
+            format!("{}.rowid", quote_identifier(&self.name))
+        } else {
+            "o.id".to_string()
+        };
+        // one more row than the batch tells whether another batch follows
+        let batch_size = params.hits_per_page.max(1);
+        let mut binds = matching.binds.clone();
+        binds.push((":after".to_string(), sqlite::Value::Integer(after)));
+        binds.push((
+            ":limit".to_string(),
+            sqlite::Value::Integer(i64::try_from(batch_size).unwrap_or(i64::MAX - 1) + 1),
+        ));
+        let mut statement = bind_all(
+            self.db_connection.prepare(format!(
+                "SELECT o.id, o.object_id, o.document {} ORDER BY {} LIMIT :limit",
+                matching.and_where(&format!("{} > :after", rowid)),
+                rowid
+            ))?,
+            &binds,
+        )?;
+        let unretrievable = settings::list(&self.settings, "unretrievableAttributes");
+        let mut last = None;
+        while let State::Row = statement.next()? {
+            if rs.hits.len() as u64 == batch_size {
+                rs.processing_time_ms = started.elapsed().as_millis();
+                return Ok((rs, last));
+            }
+            let document = statement.read::<Option<String>>(2)?.unwrap_or_default();
+            let mut hit = json::parse(&document).map_err(|e| IndexError::Storage(e.to_string()))?;
+            hit[OBJECT_ID] = statement.read::<String>(1)?.into();
+            hit = remove_attributes(hit, &unretrievable);
+            rs.hits.push(retrieve_attributes(
+                hit,
+                params.attributes_to_retrieve.as_deref(),
+            ));
+            last = Some(statement.read::<i64>(0)?);
+        }
+
+        rs.processing_time_ms = started.elapsed().as_millis();
+        Ok((rs, None))
+    }
+
+    // the bm25() call ranking the matches. columns left out of the weights
+    // weigh 1.0, and SQLite functions take at most 127 arguments: only the
+    // weights up to the last one that isn't 1.0 are passed, at most
+    // MAX_BM25_WEIGHTS. later columns weigh as the last searchableAttributes entry
+    fn score(&self, table: &str) -> String {
+        let mut weights = self.column_weights();
+        while weights.last() == Some(&1.0) {
+            weights.pop();
+        }
+        if weights.len() > MAX_BM25_WEIGHTS {
+            debug!(
+                "{}: only the first {} columns are weighted",
+                self.name, MAX_BM25_WEIGHTS
+            );
+            weights.truncate(MAX_BM25_WEIGHTS);
+        }
+        let arguments: Vec<String> = std::iter::once(table.to_string())
+            .chain(weights.iter().map(|weight| weight.to_string()))
+            .collect();
+        format!("bm25({})", arguments.join(", "))
+    }
+
+    // bm25() weight of every column, in attribute_list order. the first
+    // searchableAttributes entry weighs the most, the last one 1.0.
+    // attributes missing from the setting are left out of the MATCH
+    // expression, their weight doesn't count and stays 1.0. without the
+    // setting all columns weigh the same
+    fn column_weights(&self) -> Vec<f64> {
+        let searchable = settings::searchable_attributes(&self.settings);
+        if searchable.is_empty() {
+            return vec![1.0; self.attribute_list.len()];
+        }
+
+        self.attribute_list
+            .iter()
+            .map(|attribute| {
+                searchable
+                    .iter()
+                    .position(|names| names.iter().any(|name| attributes::covers(name, attribute)))
+                    .map(|priority| (searchable.len() - priority) as f64)
+                    .unwrap_or(1.0)
             })
-            .unwrap();
+            .collect()
+    }
 
-        serde_json::to_string(&rs)
+    // the stored settings with defaults for what was never set
+    pub fn settings(&self) -> JsonValue {
+        settings::with_defaults(&self.settings)
+    }
+
+    // merges a settings update into the stored settings
+    fn apply_settings(&mut self, update: &JsonValue) -> Result<(), IndexError> {
+        let merged = settings::merge(&self.settings, update)?;
+        let reindex = self.settings["attributesForFaceting"] != merged["attributesForFaceting"];
+        self.settings = merged;
+        if reindex {
+            self.rebuild_values()?;
+        }
+        Ok(())
     }
 
-    pub fn index_string_document(&mut self, body: String) {
-        let doc = json::parse(&body).unwrap();
+    // queues a write, returning its taskID. what can be checked without
+    // applying it is checked here, so most bad requests are refused up front
+    pub fn enqueue(&mut self, task: &Task) -> Result<u64, IndexError> {
+        if self.closed {
+            return Err(IndexError::NotFound("Index does not exist".to_string()));
+        }
+        validate_task(&self.name, &self.settings, task)?;
+
+        let (kind, payload) = task.to_json();
+        self.insert_task(kind, &payload, tasks::NOT_PUBLISHED)
+    }
+
+    fn insert_task(
+        &self,
+        kind: &str,
+        payload: &JsonValue,
+        status: &str,
+    ) -> Result<u64, IndexError> {
+        let mut statement = self
+            .db_connection
+            .prepare(format!(
+                "INSERT INTO {} (kind, payload, status, created_at) VALUES (?, ?, ?, ?)",
+                TASKS_TABLE
+            ))?
+            .bind(1, kind)?
+            .bind(2, payload.dump().as_str())?
+            .bind(3, status)?
+            .bind(4, Local::now().timestamp_millis())?;
+        statement.next()?;
+
+        let mut statement = self.db_connection.prepare("SELECT last_insert_rowid()")?;
+        statement.next()?;
+        Ok(statement.read::<i64>(0)? as u64)
+    }
+
+    // applies every pending task, for operations that must come after the
+    // writes queued before them
+    pub fn apply_pending_tasks(&mut self) -> Result<(), IndexError> {
+        while self.run_next_task()? {}
+        Ok(())
+    }
+
+    // applies the oldest pending task. the task is marked published in the
+    // same transaction as its writes. a failing task is rolled back and
+    // marked published with its error, the next ones still run.
+    // returns false when nothing is pending
+    pub fn run_next_task(&mut self) -> Result<bool, IndexError> {
+        if self.closed {
+            return Ok(false);
+        }
+        let mut statement = self
+            .db_connection
+            .prepare(format!(
+                "SELECT id, kind, payload FROM {} WHERE status = ? ORDER BY id LIMIT 1",
+                TASKS_TABLE
+            ))?
+            .bind(1, tasks::NOT_PUBLISHED)?;
+        if statement.next()? == State::Done {
+            return Ok(false);
+        }
+        let task_id = statement.read::<i64>(0)?;
+        let kind = statement.read::<String>(1)?;
+        let payload = statement.read::<String>(2)?;
+        drop(statement);
+
+        let started = Instant::now();
+        let result = self.write_transaction(|ie| {
+            let payload = json::parse(&payload).map_err(|e| IndexError::Storage(e.to_string()))?;
+            let mut deleted = 0;
+            match Task::from_json(&kind, &payload)? {
+                Task::Batch(actions) => ie.apply_batch(&actions)?,
+                // the records matching when the task runs, after the
+                // writes queued before it
+                Task::DeleteByQuery(params) => {
+                    let object_ids = ie.matching_object_ids(&params)?;
+                    for object_id in &object_ids {
+                        ie.delete_document(object_id)?;
+                    }
+                    deleted = object_ids.len() as u64;
+                }
+                Task::Settings(update) => ie.apply_settings(&update)?,
+                Task::SaveSynonyms { synonyms, replace } => {
+                    if replace {
+                        ie.clear_synonyms()?;
+                    }
+                    ie.save_synonyms(&synonyms)?
+                }
+                Task::DeleteSynonym(object_id) => ie.delete_synonym(&object_id)?,
+                Task::ClearSynonyms => ie.clear_synonyms()?,
+            }
+            ie.last_task_id = task_id as u64;
+            ie.last_build_time_s = started.elapsed().as_secs();
+            ie.publish_task(task_id, None)?;
+            Ok(deleted)
+        });
+        match result {
+            Ok(deleted) => self.deleted_by_query += deleted,
+            Err(e) => {
+                info!("task {} failed: {}", task_id, e);
+                self.last_task_id = task_id as u64;
+                self.save_metadata()?;
+                self.publish_task(task_id, Some(&e.to_string()))?;
+            }
+        }
+        Ok(true)
+    }
+
+    // the records deleteByQuery tasks removed since the last call
+    pub fn take_deleted_by_query(&mut self) -> u64 {
+        std::mem::take(&mut self.deleted_by_query)
+    }
+
+    fn publish_task(&self, task_id: i64, error: Option<&str>) -> Result<(), IndexError> {
+        let mut statement = self
+            .db_connection
+            .prepare(format!(
+                "UPDATE {} SET status = ?, error = ? WHERE id = ?",
+                TASKS_TABLE
+            ))?
+            .bind(1, tasks::PUBLISHED)?
+            .bind(2, error)?
+            .bind(3, task_id)?;
+        statement.next()?;
+        Ok(())
+    }
+
+    // the status of a task and the error it failed with. tasks applied
+    // before they were queued are published
+    pub fn task_status(&self, task_id: u64) -> Result<(String, Option<String>), IndexError> {
+        let mut statement = self
+            .db_connection
+            .prepare(format!(
+                "SELECT status, error FROM {} WHERE id = ?",
+                TASKS_TABLE
+            ))?
+            .bind(1, task_id as i64)?;
+        match statement.next()? {
+            State::Row => Ok((statement.read::<String>(0)?, statement.read(1)?)),
+            State::Done if task_id > 0 && task_id <= self.last_task_id => {
+                Ok((tasks::PUBLISHED.to_string(), None))
+            }
+            State::Done => Err(IndexError::NotFound("Task does not exist".to_string())),
+        }
+    }
+
+    // records a write applied outside of the queue, such as a copy of the
+    // index, as a published task. returns its taskID
+    pub fn record_task(&mut self, kind: &str, payload: &JsonValue) -> Result<u64, IndexError> {
+        if self.closed {
+            return Err(IndexError::NotFound("Index does not exist".to_string()));
+        }
+        self.last_task_id = self.insert_task(kind, payload, tasks::PUBLISHED)?;
+        self.save_metadata()?;
+        Ok(self.last_task_id)
+    }
 
-        self.index_jsonvalue(doc)
+    pub fn document_count(&self) -> u64 {
+        self.document_count
     }
 
-    pub fn index_jsonvalue(&mut self, doc: JsonValue) {
-        let mut attribute_list: Vec<String> = vec![];
-        let mut value_list: Vec<String> = vec![];
+    // the entry of the index in the list of indexes
+    pub fn summary(&self) -> Result<JsonValue, IndexError> {
+        let mut statement = self.db_connection.prepare(
+            "SELECT (page_count - freelist_count) * page_size \
+             FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size()",
+        )?;
+        statement.next()?;
+        let data_size = statement.read::<i64>(0)?;
+        drop(statement);
+        let mut statement = self
+            .db_connection
+            .prepare(format!(
+                "SELECT count(*) FROM {} WHERE status = ?",
+                TASKS_TABLE
+            ))?
+            .bind(1, tasks::NOT_PUBLISHED)?;
+        statement.next()?;
+        let pending_tasks = statement.read::<i64>(0)?;
+        let file_size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+
+        Ok(object! {
+            name: self.name.clone(),
+            createdAt: rfc3339(self.created_at),
+            updatedAt: rfc3339(self.updated_at),
+            entries: self.document_count,
+            dataSize: data_size,
+            fileSize: file_size,
+            lastBuildTimeS: self.last_build_time_s,
+            numberOfPendingTasks: pending_tasks,
+            pendingTask: pending_tasks > 0,
+        })
+    }
+
+    // writes a consistent copy of the index database to `path`, for an
+    // index called `name`. the copy has what was applied so far, the pending
+    // tasks are applied first, see IndexManager::copy_index
+    pub fn export(&self, path: &Path, name: &str) -> Result<(), IndexError> {
+        if let Some(attribute) = self
+            .attribute_list
+            .iter()
+            .find(|attribute| attribute.eq_ignore_ascii_case(name))
+        {
+            return Err(IndexError::InvalidParameter(format!(
+                "attribute {} can't be indexed in an index called {}",
+                attribute, name
+            )));
+        }
+        remove_file(path)?;
+        let target = path.to_string_lossy();
+        let mut statement = self
+            .db_connection
+            .prepare("VACUUM INTO ?")?
+            .bind(1, target.as_ref())?;
+        statement.next()?;
+        drop(statement);
+
+        // the FTS5 table is named after its index
+        let copy = sqlite::open(path)?;
+        if self.table_exists(&self.name)? {
+            copy.execute(format!(
+                "ALTER TABLE {} RENAME TO {}",
+                quote_identifier(&self.name),
+                quote_identifier(name)
+            ))?;
+        }
+        for (key, value) in [
+            ("name", name.to_string()),
+            ("version", Uuid::new_v4().to_string()),
+        ] {
+            let mut statement = copy
+                .prepare(format!(
+                    "UPDATE {} SET value = ? WHERE key = ?",
+                    METADATA_TABLE
+                ))?
+                .bind(1, value.as_str())?
+                .bind(2, key)?;
+            statement.next()?;
+        }
+        Ok(())
+    }
+
+    // gives the copy at `path`, about to replace this index, the tasks of
+    // this index instead of the ones of its source, so the taskIDs handed
+    // out here keep their meaning. pending tasks are dropped with the index
+    // they were written for, and report published like the other old IDs
+    pub fn hand_over_tasks(&self, path: &Path) -> Result<(), IndexError> {
+        let mut statement = self
+            .db_connection
+            .prepare("SELECT seq FROM sqlite_sequence WHERE name = ?")?
+            .bind(1, TASKS_TABLE)?;
+        let last_task_id = match statement.next()? {
+            State::Row => (statement.read::<i64>(0)? as u64).max(self.last_task_id),
+            State::Done => self.last_task_id,
+        };
+        drop(statement);
+
+        let copy = sqlite::open(path)?;
+        let source = self.path.to_string_lossy();
+        let mut statement = copy
+            .prepare("ATTACH DATABASE ? AS replaced")?
+            .bind(1, source.as_ref())?;
+        statement.next()?;
+        drop(statement);
+
+        copy.execute("BEGIN")?;
+        copy.execute(format!("DELETE FROM {}", TASKS_TABLE))?;
+        let mut statement = copy
+            .prepare(format!(
+                "INSERT INTO {} SELECT * FROM replaced.{} WHERE status = ?",
+                TASKS_TABLE, TASKS_TABLE
+            ))?
+            .bind(1, tasks::PUBLISHED)?;
+        statement.next()?;
+        drop(statement);
+        let mut statement = copy
+            .prepare("DELETE FROM sqlite_sequence WHERE name = ?")?
+            .bind(1, TASKS_TABLE)?;
+        statement.next()?;
+        drop(statement);
+        let mut statement = copy
+            .prepare("INSERT INTO sqlite_sequence (name, seq) VALUES (?, ?)")?
+            .bind(1, TASKS_TABLE)?
+            .bind(2, last_task_id as i64)?;
+        statement.next()?;
+        drop(statement);
+        let mut statement = copy
+            .prepare(format!(
+                "INSERT OR REPLACE INTO {} (key, value) VALUES ('last_task_id', ?)",
+                METADATA_TABLE
+            ))?
+            .bind(1, last_task_id.to_string().as_str())?;
+        statement.next()?;
+        drop(statement);
+        copy.execute("COMMIT")?;
+        copy.execute("DETACH DATABASE replaced")?;
+        Ok(())
+    }
+
+    // replaces the settings, for a copy of the settings of another index
+    pub fn replace_settings(&mut self, settings: &JsonValue) -> Result<(), IndexError> {
+        self.write_transaction(|ie| {
+            let reindex = ie.settings["attributesForFaceting"] != settings["attributesForFaceting"];
+            ie.settings = settings.clone();
+            if reindex {
+                ie.rebuild_values()?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn stored_settings(&self) -> &JsonValue {
+        &self.settings
+    }
+
+    // replaces the synonyms, for a copy of the synonyms of another index
+    pub fn replace_synonyms(&mut self, synonyms: &[Synonym]) -> Result<(), IndexError> {
+        self.write_transaction(|ie| {
+            ie.clear_synonyms()?;
+            ie.save_synonyms(synonyms)
+        })
+    }
+
+    pub fn stored_synonyms(&self) -> &[Synonym] {
+        &self.synonyms
+    }
+
+    pub fn get_synonym(&self, object_id: &str) -> Result<JsonValue, IndexError> {
+        self.synonyms
+            .iter()
+            .find(|synonym| synonym.object_id == object_id)
+            .map(|synonym| synonym.to_json())
+            .ok_or_else(|| IndexError::NotFound("Synonym set does not exist".to_string()))
+    }
+
+    // one page of the synonym sets mentioning the query, of the given
+    // types (all of them when empty), and how many there are in all
+    pub fn search_synonyms(
+        &self,
+        query: &str,
+        types: &[&str],
+        page: u64,
+        hits_per_page: u64,
+    ) -> (Vec<JsonValue>, u64) {
+        let found: Vec<&Synonym> = self
+            .synonyms
+            .iter()
+            .filter(|synonym| types.is_empty() || types.contains(&synonym.kind()))
+            .filter(|synonym| synonym.mentions(query))
+            .collect();
+        let hits = found
+            .iter()
+            .skip(usize::try_from(page.saturating_mul(hits_per_page)).unwrap_or(usize::MAX))
+            .take(usize::try_from(hits_per_page).unwrap_or(usize::MAX))
+            .map(|synonym| synonym.to_json())
+            .collect();
+        (hits, found.len() as u64)
+    }
+
+    // stores synonym sets, replacing those with the same objectID
+    fn save_synonyms(&mut self, synonyms: &[Synonym]) -> Result<(), IndexError> {
+        for synonym in synonyms {
+            let mut statement = self
+                .db_connection
+                .prepare(format!(
+                    "INSERT OR REPLACE INTO {} (object_id, synonym) VALUES (?, ?)",
+                    SYNONYMS_TABLE
+                ))?
+                .bind(1, synonym.object_id.as_str())?
+                .bind(2, synonym.to_json().dump().as_str())?;
+            statement.next()?;
+            self.synonyms.retain(|s| s.object_id != synonym.object_id);
+            self.synonyms.push(synonym.clone());
+        }
+        self.synonyms.sort_by(|a, b| a.object_id.cmp(&b.object_id));
+        Ok(())
+    }
+
+    // deleting a missing synonym set does nothing
+    fn delete_synonym(&mut self, object_id: &str) -> Result<(), IndexError> {
+        let mut statement = self
+            .db_connection
+            .prepare(format!(
+                "DELETE FROM {} WHERE object_id = ?",
+                SYNONYMS_TABLE
+            ))?
+            .bind(1, object_id)?;
+        statement.next()?;
+        self.synonyms.retain(|s| s.object_id != object_id);
+        Ok(())
+    }
+
+    fn clear_synonyms(&mut self) -> Result<(), IndexError> {
+        self.execute(format!("DELETE FROM {}", SYNONYMS_TABLE))?;
+        self.synonyms.clear();
+        Ok(())
+    }
+
+    // stops the index for good: no more tasks are applied or queued.
+    // for an index being deleted or replaced by another one
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    // takes back a close, for a move that could not replace its destination
+    pub fn reopen(&mut self) {
+        self.closed = false;
+    }
+
+    // closes the index and removes its database. the connection keeps the
+    // unlinked file until the last handler using the index is done
+    pub fn delete_files(&mut self) -> Result<(), IndexError> {
+        self.close();
+        for suffix in ["", "-journal", "-wal", "-shm"] {
+            let mut path = self.path.clone().into_os_string();
+            path.push(suffix);
+            remove_file(Path::new(&path))?;
+        }
+        Ok(())
+    }
+
+    // applies every batch action, in the transaction of its task: either
+    // all of them are stored or none is
+    fn apply_batch(&mut self, actions: &[BatchAction]) -> Result<(), IndexError> {
+        for (position, action) in actions.iter().enumerate() {
+            let batch_error = |e| IndexError::Batch {
+                position,
+                error: Box::new(e),
+            };
+            // the schema gets the attributes of every document up to the
+            // next delete at once, rather than a rebuild per document
+            if position == 0 || matches!(actions[position - 1], BatchAction::Delete) {
+                let until = actions[position..]
+                    .iter()
+                    .position(|action| matches!(action, BatchAction::Delete))
+                    .map_or(actions.len(), |next| position + next);
+                let docs = actions[position..until]
+                    .iter()
+                    .filter_map(|action| match action {
+                        BatchAction::AddObject(doc)
+                        | BatchAction::UpdateObject(doc)
+                        | BatchAction::PartialUpdateObject { body: doc, .. } => Some(doc),
+                        _ => None,
+                    });
+                self.extend_schema(docs).map_err(batch_error)?;
+            }
+            self.apply(action).map_err(batch_error)?;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, action: &BatchAction) -> Result<Option<String>, IndexError> {
+        match action {
+            BatchAction::AddObject(doc) | BatchAction::UpdateObject(doc) => {
+                self.upsert_document(doc).map(Some)
+            }
+            BatchAction::PartialUpdateObject {
+                body,
+                create_if_not_exists,
+            } => self
+                .partial_update_document(body, *create_if_not_exists)
+                .map(Some),
+            BatchAction::DeleteObject(object_id) => {
+                self.delete_document(object_id)?;
+                Ok(Some(object_id.clone()))
+            }
+            BatchAction::Delete => {
+                self.clear_documents()?;
+                if self.table_exists(&self.name)? {
+                    self.execute(format!("DROP TABLE {}", quote_identifier(&self.name)))?;
+                }
+                self.attribute_list = Vec::new();
+                self.settings = JsonValue::new_object();
+                Ok(None)
+            }
+            BatchAction::Clear => {
+                self.clear_documents()?;
+                Ok(None)
+            }
+        }
+    }
+
+    // a stored document, optionally restricted to some attributes
+    pub fn get_object(
+        &self,
+        object_id: &str,
+        attributes_to_retrieve: Option<&[String]>,
+    ) -> Result<JsonValue, IndexError> {
+        let doc = match self.read_document(object_id)? {
+            Some(doc) => doc,
+            None => return Err(IndexError::NotFound("ObjectID does not exist".to_string())),
+        };
+
+        let unretrievable = settings::list(&self.settings, "unretrievableAttributes");
+        Ok(retrieve_attributes(
+            remove_attributes(doc, &unretrievable),
+            attributes_to_retrieve,
+        ))
+    }
+
+    // a document with a known objectID replaces the stored one, keeping its rowid.
+    // documents without an objectID get a generated one
+    fn upsert_document(&mut self, doc: &JsonValue) -> Result<String, IndexError> {
+        if !doc.is_object() {
+            return Err(IndexError::InvalidDocument(
+                "document must be a JSON object".to_string(),
+            ));
+        }
         debug!("doc: {}", doc);
         debug!("schema: {:?}", self.attribute_list);
 
-        for tag in doc.entries() {
-            println!("Element: {:?}: {:?}", tag.0, tag.1.to_string());
-            attribute_list.push(tag.0.to_string());
-            value_list.push(format!("'{}'", tag.1));
+        validate_attributes(&self.name, doc)?;
+        self.extend_schema(std::iter::once(doc))?;
+
+        let object_id = document_object_id(doc)?;
+        let table = quote_identifier(&self.name);
+
+        let mut attribute_list: Vec<String> = vec!["rowid".to_string()];
+        let mut value_list: Vec<sqlite::Value> = vec![];
+        for (attribute, value) in attributes::flatten(doc) {
+            if attribute == OBJECT_ID {
+                continue;
+            }
+            validate_attribute(&self.name, &attribute)?;
+            attribute_list.push(quote_identifier(&attribute));
+            value_list.push(match attributes::text(&value) {
+                Some(text) => sqlite::Value::String(synonyms::mark_placeholders(&text)),
+                None => sqlite::Value::Null,
+            });
         }
+        // the document as given, objectID included
+        let mut document = doc.clone();
+        document[OBJECT_ID] = object_id.clone().into();
+
+        let rowid = match self.object_rowid(&object_id)? {
+            Some(rowid) => {
+                let mut statement = self
+                    .db_connection
+                    .prepare(format!("DELETE FROM {} WHERE rowid = ?", table))?
+                    .bind(1, rowid)?;
+                statement.next()?;
+                let mut statement = self
+                    .db_connection
+                    .prepare(format!(
+                        "UPDATE {} SET document = ? WHERE id = ?",
+                        OBJECTS_TABLE
+                    ))?
+                    .bind(1, document.dump().as_str())?
+                    .bind(2, rowid)?;
+                statement.next()?;
+                rowid
+            }
+            None => {
+                let mut statement = self
+                    .db_connection
+                    .prepare(format!(
+                        "INSERT INTO {} (object_id, document) VALUES (?, ?)",
+                        OBJECTS_TABLE
+                    ))?
+                    .bind(1, object_id.as_str())?
+                    .bind(2, document.dump().as_str())?;
+                statement.next()?;
+                self.document_count += 1;
+                self.object_rowid(&object_id)?.ok_or_else(|| {
+                    IndexError::Storage(format!("objectID {} was not stored", object_id))
+                })?
+            }
+        };
 
         let insert_statement = format!(
-            "INSERT into {} ({}) VALUES ({})",
-            self.name,
+            "INSERT INTO {} ({}) VALUES ({})",
+            table,
             attribute_list.join(","),
-            value_list.into_iter().collect::<Vec<String>>().join(",")
+            vec!["?"; attribute_list.len()].join(",")
         );
+        debug!("insert: {}", insert_statement);
+
+        let mut statement = self
+            .db_connection
+            .prepare(&insert_statement)?
+            .bind(1, rowid)?;
+        for (i, value) in value_list.iter().enumerate() {
+            statement = statement.bind(i + 2, value)?;
+        }
+        statement.next()?;
+
+        self.index_values(rowid, &document)?;
 
-        match self.db_connection.execute(insert_statement.clone()) {
-            Ok(v) => debug!("ok: {:?} - {}", v, insert_statement),
-            Err(e) => info!("error: {} - {}", e, insert_statement),
+        Ok(object_id)
+    }
+
+    // merges the given attributes into the stored document
+    fn partial_update_document(
+        &mut self,
+        body: &JsonValue,
+        create_if_not_exists: bool,
+    ) -> Result<String, IndexError> {
+        let object_id = document_object_id(body)?;
+        let mut doc = match self.read_document(&object_id)? {
+            Some(doc) => doc,
+            None if create_if_not_exists => JsonValue::new_object(),
+            None => return Ok(object_id),
         };
+        for (attribute, value) in body.entries() {
+            doc[attribute] = value.clone();
+        }
+        doc[OBJECT_ID] = object_id.into();
+
+        self.upsert_document(&doc)
     }
 
-    pub fn create_schema_from_json(&mut self, doc: JsonValue) {
-        let mut attribute_list: Vec<String> = vec![];
-        let local_doc = doc.clone();
-        debug!("doc: {}", local_doc);
+    // returns false when the objectID isn't in the index
+    fn delete_document(&mut self, object_id: &str) -> Result<bool, IndexError> {
+        let rowid = match self.object_rowid(object_id)? {
+            Some(rowid) => rowid,
+            None => return Ok(false),
+        };
+
+        if self.table_exists(&self.name)? {
+            let mut statement = self
+                .db_connection
+                .prepare(format!(
+                    "DELETE FROM {} WHERE rowid = ?",
+                    quote_identifier(&self.name)
+                ))?
+                .bind(1, rowid)?;
+            statement.next()?;
+        }
+        for side_table in [OBJECTS_TABLE, FACETS_TABLE, ATTRIBUTES_TABLE] {
+            let mut statement = self
+                .db_connection
+                .prepare(format!("DELETE FROM {} WHERE id = ?", side_table))?
+                .bind(1, rowid)?;
+            statement.next()?;
+        }
+        self.document_count = self.document_count.saturating_sub(1);
+
+        Ok(true)
+    }
+
+    fn clear_documents(&mut self) -> Result<(), IndexError> {
+        if self.table_exists(&self.name)? {
+            self.execute(format!("DELETE FROM {}", quote_identifier(&self.name)))?;
+        }
+        self.execute(format!("DELETE FROM {}", OBJECTS_TABLE))?;
+        self.execute(format!("DELETE FROM {}", FACETS_TABLE))?;
+        self.execute(format!("DELETE FROM {}", ATTRIBUTES_TABLE))?;
+        self.document_count = 0;
+        Ok(())
+    }
+
+    // replaces the facet and typed values of a document
+    fn index_values(&self, rowid: i64, doc: &JsonValue) -> Result<(), IndexError> {
+        for side_table in [FACETS_TABLE, ATTRIBUTES_TABLE] {
+            let mut statement = self
+                .db_connection
+                .prepare(format!("DELETE FROM {} WHERE id = ?", side_table))?
+                .bind(1, rowid)?;
+            statement.next()?;
+        }
+
+        let mut faceting: Vec<String> = settings::faceting_attributes(&self.settings)
+            .into_iter()
+            .map(|facet| facet.name)
+            .collect();
+        if !faceting.iter().any(|name| name == TAGS) {
+            faceting.push(TAGS.to_string());
+        }
+
+        for (attribute, value) in attributes::flatten(doc) {
+            if attribute == OBJECT_ID {
+                continue;
+            }
+            if faceting.contains(&attribute) {
+                for (facet_value, number) in facets::values(&value) {
+                    let mut statement = self
+                        .db_connection
+                        .prepare(format!(
+                            "INSERT INTO {} (id, attribute, value, number) VALUES (?, ?, ?, ?)",
+                            FACETS_TABLE
+                        ))?
+                        .bind(1, rowid)?
+                        .bind(2, attribute.as_str())?
+                        .bind(3, facet_value.as_str())?
+                        .bind(4, number)?;
+                    statement.next()?;
+                }
+            }
+            for (value_type, typed_value) in attributes::typed_values(&value) {
+                let mut statement = self
+                    .db_connection
+                    .prepare(format!(
+                        "INSERT INTO {} (id, attribute, type, value) VALUES (?, ?, ?, ?)",
+                        ATTRIBUTES_TABLE
+                    ))?
+                    .bind(1, rowid)?
+                    .bind(2, attribute.as_str())?
+                    .bind(3, value_type)?
+                    .bind(4, &typed_value)?;
+                statement.next()?;
+            }
+        }
+        Ok(())
+    }
+
+    // facet and typed values of every document, after attributesForFaceting
+    // changed or for databases written before the side tables existed
+    fn rebuild_values(&mut self) -> Result<(), IndexError> {
+        self.execute(format!("DELETE FROM {}", FACETS_TABLE))?;
+        self.execute(format!("DELETE FROM {}", ATTRIBUTES_TABLE))?;
+
+        let mut documents = vec![];
+        let mut statement = self.db_connection.prepare(format!(
+            "SELECT id, document FROM {} WHERE document IS NOT NULL",
+            OBJECTS_TABLE
+        ))?;
+        while let State::Row = statement.next()? {
+            if let Ok(doc) = json::parse(&statement.read::<String>(1)?) {
+                documents.push((statement.read::<i64>(0)?, doc));
+            }
+        }
+        drop(statement);
+
+        for (rowid, doc) in documents {
+            self.index_values(rowid, &doc)?;
+        }
+        Ok(())
+    }
 
-        for tag in local_doc.entries() {
-            let tag = tag;
-            debug!("Element: {:?}: {:?}", tag.0, tag.1.to_string());
-            attribute_list.push(tag.0.to_string());
+    // values of a searchable facet matching the facetQuery, counted over the
+    // documents matching the query and filters. most frequent first
+    pub fn search_facet_values(
+        &mut self,
+        facet: &str,
+        params: &SearchParams,
+    ) -> Result<JsonValue, IndexError> {
+        let started = Instant::now();
+        let params = &params.with_settings(&self.settings);
+        let facet = attributes::path(facet);
+        let facet = facet.as_str();
+        if !settings::faceting_attributes(&self.settings)
+            .iter()
+            .any(|f| f.name == facet && f.searchable)
+        {
+            return Err(IndexError::InvalidParameter(format!(
+                "Cannot search in `{}` attribute, you need to add `searchable({})` to attributesForFaceting.",
+                facet, facet
+            )));
         }
 
+        let mut facet_hits = vec![];
+        if let Some(matching) = self.matching(params)? {
+            let mut binds = matching.binds.clone();
+            binds.push((
+                ":facet".to_string(),
+                sqlite::Value::String(facet.to_string()),
+            ));
+            let mut statement = bind_all(
+                self.db_connection.prepare(format!(
+                    "SELECT value, count(DISTINCT id) AS n FROM {} \
+                     WHERE attribute = :facet AND id IN (SELECT {}.rowid {}) \
+                     GROUP BY value ORDER BY n DESC, value",
+                    FACETS_TABLE,
+                    quote_identifier(&self.name),
+                    matching.from
+                ))?,
+                &binds,
+            )?;
+            while let State::Row = statement.next()? {
+                if facet_hits.len() as u64 >= params.max_facet_hits {
+                    break;
+                }
+                let value = statement.read::<String>(0)?;
+                if let Some(highlighted) = highlight::highlight_facet_value(
+                    &value,
+                    &params.facet_query,
+                    &params.highlight_pre_tag,
+                    &params.highlight_post_tag,
+                ) {
+                    facet_hits.push(object! {
+                        value: value,
+                        highlighted: highlighted,
+                        count: statement.read::<i64>(1)?,
+                    });
+                }
+            }
+        }
+
+        Ok(object! {
+            facetHits: facet_hits,
+            exhaustiveFacetsCount: true,
+            processingTimeMS: started.elapsed().as_millis() as u64,
+        })
+    }
+
+    // value counts and numeric stats of the requested facets over every
+    // document matching the search. `from` is the FROM/WHERE of the search
+    fn facet_counts(
+        &self,
+        from: &str,
+        binds: &[(String, sqlite::Value)],
+        params: &SearchParams,
+    ) -> Result<(JsonValue, JsonValue), IndexError> {
+        let mut facets = JsonValue::new_object();
+        let mut facets_stats = JsonValue::new_object();
+        let attributes: Vec<String> = settings::faceting_attributes(&self.settings)
+            .into_iter()
+            .filter(|facet| {
+                !facet.filter_only
+                    && params
+                        .facets
+                        .iter()
+                        .any(|f| f == "*" || attributes::path(f) == facet.name)
+            })
+            .map(|facet| facet.name)
+            .collect();
+        if attributes.is_empty() {
+            return Ok((facets, facets_stats));
+        }
+
+        let names: Vec<String> = (0..attributes.len())
+            .map(|i| format!(":facet{}", i))
+            .collect();
+        let matching = format!(
+            "attribute IN ({}) AND id IN (SELECT {}.rowid {})",
+            names.join(", "),
+            quote_identifier(&self.name),
+            from
+        );
+        let mut binds = binds.to_vec();
+        for (name, attribute) in names.iter().zip(attributes.iter()) {
+            binds.push((name.clone(), sqlite::Value::String(attribute.clone())));
+        }
+
+        let mut statement = bind_all(
+            self.db_connection.prepare(format!(
+                "SELECT attribute, value, count(DISTINCT id) AS n FROM {} WHERE {} \
+                 GROUP BY attribute, value ORDER BY attribute, n DESC, value",
+                FACETS_TABLE, matching
+            ))?,
+            &binds,
+        )?;
+        let mut counts: HashMap<String, Vec<(String, i64)>> = HashMap::new();
+        while let State::Row = statement.next()? {
+            let values = counts.entry(statement.read::<String>(0)?).or_default();
+            if (values.len() as u64) < params.max_values_per_facet {
+                values.push((statement.read::<String>(1)?, statement.read::<i64>(2)?));
+            }
+        }
+
+        let mut statement = bind_all(
+            self.db_connection.prepare(format!(
+                "SELECT attribute, min(number), max(number), avg(number), sum(number) FROM {} \
+                 WHERE number IS NOT NULL AND {} GROUP BY attribute",
+                FACETS_TABLE, matching
+            ))?,
+            &binds,
+        )?;
+        while let State::Row = statement.next()? {
+            facets_stats[statement.read::<String>(0)?.as_str()] = object! {
+                min: statement.read::<f64>(1)?,
+                max: statement.read::<f64>(2)?,
+                avg: statement.read::<f64>(3)?,
+                sum: statement.read::<f64>(4)?,
+            };
+        }
+
+        // in attributesForFaceting order, values by count unless sorted alphabetically
+        for attribute in &attributes {
+            let mut values = counts.remove(attribute).unwrap_or_default();
+            if params.sort_facet_values_by == "alpha" {
+                values.sort_by(|a, b| a.0.cmp(&b.0));
+            }
+            let mut counts = JsonValue::new_object();
+            for (value, count) in values {
+                counts[value.as_str()] = count.into();
+            }
+            facets[attribute.as_str()] = counts;
+        }
+        Ok((facets, facets_stats))
+    }
+
+    // a document as it was indexed
+    fn read_document(&self, object_id: &str) -> Result<Option<JsonValue>, IndexError> {
+        let mut statement = self
+            .db_connection
+            .prepare(format!(
+                "SELECT document FROM {} WHERE object_id = ?",
+                OBJECTS_TABLE
+            ))?
+            .bind(1, object_id)?;
+        if statement.next()? == State::Done {
+            return Ok(None);
+        }
+
+        match statement.read::<Option<String>>(0)? {
+            Some(document) => json::parse(&document)
+                .map(Some)
+                .map_err(|e| IndexError::Storage(e.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    fn object_rowid(&self, object_id: &str) -> Result<Option<i64>, IndexError> {
+        let mut statement = self
+            .db_connection
+            .prepare(format!(
+                "SELECT id FROM {} WHERE object_id = ?",
+                OBJECTS_TABLE
+            ))?
+            .bind(1, object_id)?;
+        match statement.next()? {
+            State::Row => Ok(Some(statement.read::<i64>(0)?)),
+            State::Done => Ok(None),
+        }
+    }
+
+    // the FTS5 table has one column per attribute path. it is created from
+    // the attributes of the first documents and gets new columns when
+    // documents bring new attributes
+    fn extend_schema<'a>(
+        &mut self,
+        docs: impl Iterator<Item = &'a JsonValue>,
+    ) -> Result<(), IndexError> {
+        let mut new_attributes: Vec<String> = vec![];
+        for (attribute, _) in docs.flat_map(attributes::flatten) {
+            // column names are case insensitive
+            let known = |a: &String| a.eq_ignore_ascii_case(&attribute);
+            if attribute == OBJECT_ID
+                || self.attribute_list.iter().any(known)
+                || new_attributes.iter().any(known)
+            {
+                continue;
+            }
+            validate_attribute(&self.name, &attribute)?;
+            new_attributes.push(attribute);
+        }
+        if new_attributes.is_empty() {
+            return Ok(());
+        }
+
+        let mut attribute_list = self.attribute_list.clone();
+        attribute_list.extend(new_attributes);
+        debug!("schema: {:?}", attribute_list);
+        let table = quote_identifier(&self.name);
+        if !self.table_exists(&self.name)? {
+            self.create_fts_table(&table, &attribute_list)?;
+            self.attribute_list = attribute_list;
+            return Ok(());
+        }
+
+        // FTS5 tables can't be altered: the next generation of the table is
+        // filled from the current one, then swapped in by renaming it. this
+        // runs in the write transaction of the task, with the index locked:
+        // searches wait for it, and a failing task rolls it back
+        let next = quote_identifier(NEXT_GENERATION_TABLE);
+        self.execute(format!("DROP TABLE IF EXISTS {}", next))?;
+        self.create_fts_table(&next, &attribute_list)?;
+        let columns = self
+            .attribute_list
+            .iter()
+            .map(|attribute| quote_identifier(attribute))
+            .collect::<Vec<String>>()
+            .join(", ");
+        self.execute(format!(
+            "INSERT INTO {} (rowid, {}) SELECT rowid, {} FROM {}",
+            next, columns, columns, table
+        ))?;
+        self.execute(format!("DROP TABLE {}", table))?;
+        self.execute(format!("ALTER TABLE {} RENAME TO {}", next, table))?;
+        self.attribute_list = attribute_list;
+        Ok(())
+    }
+
+    fn create_fts_table(&self, table: &str, attribute_list: &[String]) -> Result<(), IndexError> {
         let index_statement = format!(
-            "CREATE VIRTUAL TABLE IF NOT EXISTS {} USING fts5 ({});",
-            self.name,
-            attribute_list.join(",")
+            "CREATE VIRTUAL TABLE {} USING fts5 ({});",
+            table,
+            attribute_list
+                .iter()
+                .map(|attribute| quote_identifier(attribute))
+                .collect::<Vec<String>>()
+                .join(",")
         );
         debug!("creating table: {}", index_statement);
+        self.execute(index_statement)
+    }
+}
+
+// what can be checked of a write without applying it, against the name and
+// settings of its index. an index that doesn't exist yet has no settings
+pub fn validate_task(
+    index_name: &str,
+    index_settings: &JsonValue,
+    task: &Task,
+) -> Result<(), IndexError> {
+    match task {
+        Task::Batch(actions) => validate_actions(index_name, actions),
+        Task::Settings(update) => settings::merge(index_settings, update).map(|_| ()),
+        Task::DeleteByQuery(params) => match params.filter() {
+            Some(filter) => validate_filter(index_settings, &filter),
+            None => Ok(()),
+        },
+        // synonym sets are checked when parsed
+        Task::SaveSynonyms { .. } | Task::DeleteSynonym(_) | Task::ClearSynonyms => Ok(()),
+    }
+}
+
+// the checks documents must pass to be indexed, run before queueing them
+fn validate_actions(index_name: &str, actions: &[BatchAction]) -> Result<(), IndexError> {
+    for (position, action) in actions.iter().enumerate() {
+        let doc = match action {
+            BatchAction::AddObject(doc)
+            | BatchAction::UpdateObject(doc)
+            | BatchAction::PartialUpdateObject { body: doc, .. } => doc,
+            _ => continue,
+        };
+        let validate = || {
+            if !doc.is_object() {
+                return Err(IndexError::InvalidDocument(
+                    "document must be a JSON object".to_string(),
+                ));
+            }
+            document_object_id(doc)?;
+            validate_attributes(index_name, doc)
+        };
+        validate().map_err(|e| IndexError::Batch {
+            position,
+            error: Box::new(e),
+        })?;
+    }
+    Ok(())
+}
 
-        self.db_connection.execute(index_statement).unwrap();
+// attribute:value filters compare facet values, only attributesForFaceting
+// and _tags have them
+fn validate_filter(index_settings: &JsonValue, filter: &Filter) -> Result<(), IndexError> {
+    let faceting = settings::faceting_attributes(index_settings);
+    for attribute in filter.facet_attributes() {
+        if attribute != TAGS && !faceting.iter().any(|f| f.name == attribute) {
+            return Err(IndexError::InvalidParameter(format!(
+                "Attribute {} is not in attributesForFaceting",
+                attribute
+            )));
+        }
+    }
+    Ok(())
+}
 
-        self.index_jsonvalue(doc);
+// an indexed text of a search result, without placeholder marks
+fn read_text(statement: &sqlite::Statement, column: usize) -> Result<Option<String>, IndexError> {
+    Ok(statement
+        .read::<Option<String>>(column)?
+        .map(|text| synonyms::unmark_placeholders(&text)))
+}
 
-        self.attribute_list = attribute_list.clone();
+// removes a file, if it exists
+fn remove_file(path: &Path) -> Result<(), IndexError> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(IndexError::Storage(e.to_string()))
+        }
+        _ => Ok(()),
     }
+}
 
-    pub fn create_schema_from_string(&mut self, body: String) {
-        match json::parse(&body) {
-            Ok(v) => self.create_schema_from_json(v),
-            Err(e) => info!("{:?}", e.to_string()),
-        };
+fn rfc3339(timestamp_millis: i64) -> String {
+    Utc.timestamp_millis_opt(timestamp_millis)
+        .single()
+        .map(|t| t.to_rfc3339())
+        .unwrap_or_default()
+}
+
+// restricts a document to some attributes, "*" meaning all of them.
+// objectID is always retrieved
+fn retrieve_attributes(doc: JsonValue, attributes_to_retrieve: Option<&[String]>) -> JsonValue {
+    match attributes_to_retrieve {
+        Some(attributes) if !attributes.iter().any(|a| a == "*") => {
+            let mut retrieved = JsonValue::new_object();
+            for (attribute, value) in doc.entries() {
+                if attribute == OBJECT_ID || attributes.iter().any(|a| a == attribute) {
+                    retrieved[attribute] = value.clone();
+                }
+            }
+            retrieved
+        }
+        _ => doc,
+    }
+}
+
+// the elements of an array attribute from its FTS5 text, each with its
+// highlighted text when there is one
+fn array_elements(text: &str, marked: Option<String>) -> Vec<(String, Option<String>)> {
+    if text.is_empty() {
+        return vec![];
     }
+    let marked: Option<Vec<String>> = marked.map(|marked| {
+        marked
+            .split(attributes::ARRAY_SEPARATOR)
+            .map(|element| element.to_string())
+            .collect()
+    });
+    text.split(attributes::ARRAY_SEPARATOR)
+        .enumerate()
+        .map(|(i, element)| {
+            let marked = marked.as_ref().and_then(|marked| marked.get(i).cloned());
+            (element.to_string(), marked)
+        })
+        .collect()
 }
 
-// tests
-// curl -vvv localhost:3000/i/livros?q=amigos+';'+select+from+livros
+fn bind_all<'a>(
+    statement: sqlite::Statement<'a>,
+    binds: &[(String, sqlite::Value)],
+) -> Result<sqlite::Statement<'a>, IndexError> {
+    let mut statement = statement;
+    for (name, value) in binds {
+        statement = statement.bind_by_name(name, value)?;
+    }
+    Ok(statement)
+}
+
+// drops attributes a document must never show, objectID is always kept
+fn remove_attributes(doc: JsonValue, attributes: &[String]) -> JsonValue {
+    if attributes.is_empty() {
+        return doc;
+    }
+    let mut kept = JsonValue::new_object();
+    for (attribute, value) in doc.entries() {
+        if attribute == OBJECT_ID || !attributes.iter().any(|a| a == attribute) {
+            kept[attribute] = value.clone();
+        }
+    }
+    kept
+}
+
+// ORDER BY terms for a customRanking attribute, sorting on its typed values:
+// numbers sort before text, booleans as 0/1, arrays by their lowest value
+// ascending and highest descending. documents without the attribute come last
+fn custom_order(
+    table: &str,
+    attribute: &str,
+    direction: &str,
+    binds: &mut Vec<(String, sqlite::Value)>,
+) -> Vec<String> {
+    let name = format!(":rank{}", binds.len());
+    binds.push((name.clone(), sqlite::Value::String(attribute.to_string())));
+    let value = format!(
+        "(SELECT value FROM {} a WHERE a.id = {}.rowid AND a.attribute = {} ORDER BY value {} LIMIT 1)",
+        ATTRIBUTES_TABLE, table, name, direction
+    );
+    vec![
+        format!("{} IS NULL", value),
+        format!("{} {}", value, direction),
+    ]
+}
+
+// Algolia's _rankingInfo. morocco has no typos, proximity or geo criteria,
+// those are reported as neutral. bm25 is the raw FTS5 score (lower is better)
+fn ranking_info(hit: &JsonValue, terms: &[String], score: f64, user_score: u64) -> JsonValue {
+    let text: Vec<String> = hit
+        .entries()
+        .filter(|(key, _)| *key != OBJECT_ID)
+        .map(|(_, value)| value.to_string())
+        .collect();
+    let exact_words = highlight::matched_words(&text.join(" "), terms).len();
+
+    object! {
+        nbTypos: 0,
+        firstMatchedWord: 0,
+        proximityDistance: 0,
+        userScore: user_score,
+        geoDistance: 0,
+        geoPrecision: 1,
+        nbExactWords: exact_words,
+        words: terms.len(),
+        filters: 0,
+        bm25: score,
+    }
+}
+
+// objectIDs are kept as strings, numeric ones are converted like Algolia does
+fn document_object_id(doc: &JsonValue) -> Result<String, IndexError> {
+    let object_id = &doc[OBJECT_ID];
+    if object_id.is_null() {
+        Ok(Uuid::new_v4().to_string())
+    } else if object_id.is_string() || object_id.is_number() {
+        let object_id = object_id.to_string();
+        if object_id.is_empty() {
+            return Err(IndexError::InvalidDocument("objectID is empty".to_string()));
+        }
+        Ok(object_id)
+    } else {
+        Err(IndexError::InvalidDocument(
+            "objectID must be a string or a number".to_string(),
+        ))
+    }
+}
+
+// the attributes of a document, which get a column each. column names are
+// case insensitive: attributes differing only in case would share one and
+// all but one of their values would be lost, the document is refused
+fn validate_attributes(table: &str, doc: &JsonValue) -> Result<(), IndexError> {
+    let mut seen: Vec<String> = vec![];
+    for (attribute, _) in attributes::flatten(doc) {
+        if attribute == OBJECT_ID {
+            continue;
+        }
+        validate_attribute(table, &attribute)?;
+        if let Some(other) = seen.iter().find(|a| a.eq_ignore_ascii_case(&attribute)) {
+            return Err(IndexError::InvalidDocument(format!(
+                "attributes {} and {} only differ in case",
+                other, attribute
+            )));
+        }
+        seen.push(attribute);
+    }
+    Ok(())
+}
+
+// attribute names end up as FTS5 column names: they must be printable and
+// must not shadow the hidden columns FTS5 adds to every table
+fn validate_attribute(table: &str, attribute: &str) -> Result<(), IndexError> {
+    if attribute.is_empty() || attribute.chars().any(|c| c.is_control()) {
+        return Err(IndexError::InvalidAttribute(attribute.to_string()));
+    }
+    let lowered = attribute.to_lowercase();
+    if lowered == "rank" || lowered == "rowid" || lowered == table.to_lowercase() {
+        return Err(IndexError::InvalidAttribute(attribute.to_string()));
+    }
+    Ok(())
+}
+
+// table and column names can't be bound, so they are always emitted as
+// quoted identifiers with embedded quotes doubled
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
@@ -1,55 +1,154 @@
+use crate::index_engine::{self, IndexEngine, IndexError};
+use crate::stats::SearchStats;
+use crate::tasks::{self, Task};
+use json::JsonValue;
 use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+// one entry of a multi-index get objects call
+pub struct ObjectRequest {
+    pub index_name: String,
+    pub object_id: String,
+    pub attributes_to_retrieve: Option<Vec<String>>,
+}
 
 pub struct IndexManager {
     pub path: PathBuf,
-    pub index: HashMap<String, Arc<Mutex<crate::index_engine::IndexEngine>>>,
+    pub index: HashMap<String, Arc<Mutex<IndexEngine>>>,
+    // wakes up the task worker of each index
+    workers: HashMap<String, Sender<()>>,
+    // the last taskID of indexes deleted or moved away, so waiting for
+    // that task still works
+    removed: HashMap<String, u64>,
+    // updated by the task workers
+    stats: Arc<Mutex<SearchStats>>,
 }
 
 impl IndexManager {
-    pub fn new(path: PathBuf) -> IndexManager {
+    pub fn new(path: PathBuf, stats: Arc<Mutex<SearchStats>>) -> IndexManager {
         let mut im = IndexManager {
             path,
             index: HashMap::new(),
+            workers: HashMap::new(),
+            removed: HashMap::new(),
+            stats,
         };
         im.load_persistence();
         im
     }
-    pub fn create_new_index(&mut self, index_name: String, doc: String) -> Result<String, String> {
-        match self.index.get(&index_name) {
-            // check if the index is not there already
-            Some(i) => {
-                i.lock().unwrap().index_string_document(doc);
-                Ok(format!("msg: Index updated {}", index_name.clone()))
-            }
-            None => {
-                self.index.insert(
-                    index_name.clone(),
-                    Arc::new(Mutex::new(crate::index_engine::IndexEngine::new(
-                        self.path.clone(),
-                        index_name.clone(),
-                        doc,
-                    ))),
-                );
-                Ok(format!("msg: index created {}", index_name.clone()))
-            }
+    // empty indexes have no schema yet, it comes from their first document
+    pub fn get_or_create_index(
+        &mut self,
+        index_name: &str,
+    ) -> Result<Arc<Mutex<IndexEngine>>, IndexError> {
+        if let Some(i) = self.index.get(index_name) {
+            return Ok(i.clone());
+        }
+        validate_index_name(index_name)?;
+        let index_engine =
+            IndexEngine::load_or_create_index(self.path.clone(), index_name.to_string())?;
+        let index = Arc::new(Mutex::new(index_engine));
+        self.add_index(index_name.to_string(), index.clone());
+        info!("msg: index created {}", index_name);
+        Ok(index)
+    }
+
+    // an index with the channel waking up its task worker, for queueing writes
+    pub fn task_queue(
+        &mut self,
+        index_name: &str,
+        task: &Task,
+    ) -> Result<(Arc<Mutex<IndexEngine>>, Sender<()>), IndexError> {
+        // a write refused by an index that doesn't exist yet doesn't create it
+        if !self.index.contains_key(index_name) {
+            validate_index_name(index_name)?;
+            index_engine::validate_task(index_name, &JsonValue::new_object(), task)?;
+        }
+        let index = self.get_or_create_index(index_name)?;
+        Ok((index, self.workers[index_name].clone()))
+    }
+
+    // the indexes by name
+    fn sorted_indexes(&self) -> Vec<Arc<Mutex<IndexEngine>>> {
+        let mut names: Vec<&String> = self.index.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| self.index[name].clone())
+            .collect()
+    }
+
+    // whether a task of an index that was deleted or moved away was applied
+    pub fn removed_task_published(&self, index_name: &str, task_id: u64) -> Option<bool> {
+        self.removed
+            .get(index_name)
+            .map(|last_task_id| task_id <= *last_task_id)
+    }
+
+    fn operation_source(
+        &self,
+        source: &str,
+        destination: &str,
+    ) -> Result<Arc<Mutex<IndexEngine>>, IndexError> {
+        validate_index_name(destination)?;
+        if source == destination {
+            return Err(IndexError::InvalidParameter(
+                "source and destination indexes are the same".to_string(),
+            ));
+        }
+        self.index
+            .get(source)
+            .cloned()
+            .ok_or_else(|| IndexError::NotFound("Index does not exist".to_string()))
+    }
+
+    // drops a deleted or moved index, unless another one took its name since
+    fn forget_index(&mut self, index_name: &str, index: &Arc<Mutex<IndexEngine>>, task_id: u64) {
+        if self
+            .index
+            .get(index_name)
+            .is_some_and(|current| Arc::ptr_eq(current, index))
+        {
+            self.workers.remove(index_name);
+            self.index.remove(index_name);
+            self.removed.insert(index_name.to_string(), task_id);
+            self.stats
+                .lock()
+                .unwrap()
+                .remove_documents_count(index_name);
         }
     }
+
+    // takes an index out of the manager, its worker stops with it
+    fn remove_index(&mut self, index_name: &str) -> Result<Arc<Mutex<IndexEngine>>, IndexError> {
+        self.workers.remove(index_name);
+        self.index
+            .remove(index_name)
+            .ok_or_else(|| IndexError::NotFound("Index does not exist".to_string()))
+    }
+
+    fn add_index(&mut self, index_name: String, index: Arc<Mutex<IndexEngine>>) -> bool {
+        self.removed.remove(&index_name);
+        let worker = tasks::spawn_worker(index_name.clone(), index.clone(), self.stats.clone());
+        self.workers.insert(index_name.clone(), worker);
+        self.index.insert(index_name, index).is_some()
+    }
+
     fn load_existing_index(&mut self, index_name: String) -> Result<String, String> {
         // if key exists, just refresh. if not, create it
         let pp = Path::new(&index_name).to_path_buf();
         let index = pp.file_stem().unwrap();
         let clean_name = index.to_os_string().into_string().unwrap();
-        match self.index.insert(
-            clean_name.clone(),
-            Arc::new(Mutex::new(
-                crate::index_engine::IndexEngine::load_or_create_index(pp, clean_name),
-            )),
-        ) {
-            Some(_v) => Ok(format!("msg: Index updated {}", index_name)),
-            None => Ok(format!("msg: Index loaded {}", index_name)),
+        let index_engine =
+            IndexEngine::load_or_create_index(pp, clean_name.clone()).map_err(|e| e.to_string())?;
+        if self.add_index(clean_name, Arc::new(Mutex::new(index_engine))) {
+            Ok(format!("msg: Index updated {}", index_name))
+        } else {
+            Ok(format!("msg: Index loaded {}", index_name))
         }
     }
     fn load_persistence(&mut self) {
@@ -68,12 +167,256 @@ impl IndexManager {
         if dir.is_dir() {
             for entry in fs::read_dir(dir).unwrap() {
                 let db_path = entry.unwrap().path();
-                if !db_path.is_dir() {
+                // skip sqlite journals and anything else that isn't an index
+                if !db_path.is_dir() && db_path.extension().is_some_and(|e| e == "db") {
                     let index_name = db_path.to_str().unwrap().to_string();
-                    self.load_existing_index(index_name).unwrap();
+                    match self.load_existing_index(index_name) {
+                        Ok(msg) => info!("{}", msg),
+                        Err(e) => info!("error loading {:?}: {}", db_path, e),
+                    }
                 };
             }
         }
     }
     //pub fn stats() {}
 }
+
+// operations on whole indexes. the manager lock is never held while waiting
+// for an index: an index can be busy with a long task and every request
+// would wait for it. indexes are looked up with the manager locked, then
+// locked once it is released. an index can lock the manager, never the
+// other way around
+
+// every index with its size and state, by name
+pub fn list(index_manager: &Mutex<IndexManager>) -> Result<Vec<JsonValue>, IndexError> {
+    let indexes = index_manager.lock().unwrap().sorted_indexes();
+    indexes
+        .iter()
+        .map(|index| index.lock().unwrap().summary())
+        .collect()
+}
+
+// deletes an index and its database, returning the taskID of the deletion
+pub fn delete_index(
+    index_manager: &Mutex<IndexManager>,
+    index_name: &str,
+) -> Result<u64, IndexError> {
+    let index = index_manager
+        .lock()
+        .unwrap()
+        .index
+        .get(index_name)
+        .cloned()
+        .ok_or_else(|| IndexError::NotFound("Index does not exist".to_string()))?;
+    let mut index_engine = index.lock().unwrap();
+    let task_id = index_engine.record_task("delete", &JsonValue::new_object())?;
+    index_engine.delete_files()?;
+    drop(index_engine);
+
+    index_manager
+        .lock()
+        .unwrap()
+        .forget_index(index_name, &index, task_id);
+    info!("msg: index deleted {}", index_name);
+    Ok(task_id)
+}
+
+// copies an index over another one, or only some of its parts with a
+// scope. the writes queued before the copy are part of it: they are applied
+// first, the source staying locked until it is copied. returns the taskID
+// of the operation in the source index
+pub fn copy_index(
+    index_manager: &Mutex<IndexManager>,
+    source: &str,
+    destination: &str,
+    scope: Option<&[String]>,
+    request: &JsonValue,
+) -> Result<u64, IndexError> {
+    let index = index_manager
+        .lock()
+        .unwrap()
+        .operation_source(source, destination)?;
+    let mut index_engine = index.lock().unwrap();
+    index_engine.apply_pending_tasks()?;
+    let task_id = index_engine.record_task("copy", request)?;
+
+    if let Some(scope) = scope {
+        // records and everything else of the destination are kept
+        let settings = index_engine.stored_settings().clone();
+        let synonyms = index_engine.stored_synonyms().to_vec();
+        drop(index_engine);
+        let copies = |part: &str| scope.iter().any(|s| s == part);
+        if copies("settings") || copies("synonyms") {
+            let destination = index_manager
+                .lock()
+                .unwrap()
+                .get_or_create_index(destination)?;
+            let mut destination_engine = destination.lock().unwrap();
+            if copies("settings") {
+                destination_engine.replace_settings(&settings)?;
+            }
+            if copies("synonyms") {
+                destination_engine.replace_synonyms(&synonyms)?;
+            }
+        }
+        return Ok(task_id);
+    }
+
+    let copy = export_copy(index_manager, &index_engine, destination)?;
+    drop(index_engine);
+    replace_index(index_manager, &copy, destination)?;
+    Ok(task_id)
+}
+
+// renames an index, replacing the destination. the writes queued before the
+// move are applied first. returns the taskID of the operation in the
+// source index
+pub fn move_index(
+    index_manager: &Mutex<IndexManager>,
+    source: &str,
+    destination: &str,
+    request: &JsonValue,
+) -> Result<u64, IndexError> {
+    let index = index_manager
+        .lock()
+        .unwrap()
+        .operation_source(source, destination)?;
+    let mut index_engine = index.lock().unwrap();
+    index_engine.apply_pending_tasks()?;
+    let task_id = index_engine.record_task("move", request)?;
+    let copy = export_copy(index_manager, &index_engine, destination)?;
+    // closed before the lock is released, writes can't be queued on the
+    // source anymore
+    index_engine.close();
+    drop(index_engine);
+
+    if let Err(e) = replace_index(index_manager, &copy, destination) {
+        index.lock().unwrap().reopen();
+        return Err(e);
+    }
+    index.lock().unwrap().delete_files()?;
+    index_manager
+        .lock()
+        .unwrap()
+        .forget_index(source, &index, task_id);
+    info!("msg: index moved {} to {}", source, destination);
+    Ok(task_id)
+}
+
+// writes a copy of an index next to the destination database, under a name
+// of its own so operations on the same destination don't mix their copies
+fn export_copy(
+    index_manager: &Mutex<IndexManager>,
+    index_engine: &IndexEngine,
+    destination: &str,
+) -> Result<PathBuf, IndexError> {
+    let copy = index_manager.lock().unwrap().path.join(format!(
+        "{}.db.{}.copy",
+        destination,
+        Uuid::new_v4()
+    ));
+    if let Err(e) = index_engine.export(&copy, destination) {
+        let _ = fs::remove_file(&copy);
+        return Err(e);
+    }
+    Ok(copy)
+}
+
+// replaces the destination database with a copy and loads it. the copy is
+// renamed over the destination file, which is either the old one or the
+// whole copy. the replaced index is locked so none of its transactions, and
+// their journal, is left next to the copy. the manager is locked while the
+// file is renamed, the destination can't be created or replaced meanwhile
+fn replace_index(
+    index_manager: &Mutex<IndexManager>,
+    copy: &Path,
+    destination: &str,
+) -> Result<(), IndexError> {
+    loop {
+        let replaced = index_manager
+            .lock()
+            .unwrap()
+            .index
+            .get(destination)
+            .cloned();
+        let mut replaced_engine = replaced.as_ref().map(|r| r.lock().unwrap());
+        let mut manager = index_manager.lock().unwrap();
+        // another operation replaced the destination first, start over
+        if manager.index.get(destination).map(Arc::as_ptr) != replaced.as_ref().map(Arc::as_ptr) {
+            continue;
+        }
+
+        // the destination keeps its own taskIDs
+        if let Some(replaced_engine) = replaced_engine.as_ref() {
+            if let Err(e) = replaced_engine.hand_over_tasks(copy) {
+                let _ = fs::remove_file(copy);
+                return Err(e);
+            }
+        }
+        let path = manager.path.join(format!("{}.db", destination));
+        if let Err(e) = fs::rename(copy, &path) {
+            let _ = fs::remove_file(copy);
+            return Err(IndexError::Storage(e.to_string()));
+        }
+        if let Some(replaced_engine) = replaced_engine.as_mut() {
+            replaced_engine.close();
+            manager.remove_index(destination)?;
+        }
+        let index_engine = IndexEngine::load_or_create_index(path, destination.to_string())?;
+        manager.add_index(destination.to_string(), Arc::new(Mutex::new(index_engine)));
+        return Ok(());
+    }
+}
+
+// resolves objects across indexes, keeping the request order.
+// missing indexes and objects come back as null
+pub fn get_objects(
+    index_manager: &Mutex<IndexManager>,
+    requests: &[ObjectRequest],
+) -> Result<Vec<JsonValue>, IndexError> {
+    let indexes: Vec<Option<Arc<Mutex<IndexEngine>>>> = {
+        let index_manager = index_manager.lock().unwrap();
+        requests
+            .iter()
+            .map(|request| index_manager.index.get(&request.index_name).cloned())
+            .collect()
+    };
+    let mut results = vec![];
+    for (request, index) in requests.iter().zip(indexes) {
+        let index = match index {
+            Some(index) => index,
+            None => {
+                results.push(JsonValue::Null);
+                continue;
+            }
+        };
+        let object = index.lock().unwrap().get_object(
+            &request.object_id,
+            request.attributes_to_retrieve.as_deref(),
+        );
+        match object {
+            Ok(doc) => results.push(doc),
+            Err(IndexError::NotFound(_)) => results.push(JsonValue::Null),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(results)
+}
+
+// index names end up as file names and FTS5 table names
+fn validate_index_name(index_name: &str) -> Result<(), IndexError> {
+    let invalid = index_name.is_empty()
+        || index_name.len() > 256
+        || index_name.starts_with('.')
+        || index_name.to_lowercase().starts_with("_morocco")
+        || index_name
+            .chars()
+            .any(|c| c.is_control() || c == '/' || c == '\\' || c == '*');
+    if invalid {
+        return Err(IndexError::InvalidParameter(format!(
+            "Invalid index name: {}",
+            index_name
+        )));
+    }
+    Ok(())
+}
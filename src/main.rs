@@ -2,12 +2,23 @@ use actix_web::{middleware, web, App, HttpServer};
 use clap::{AppSettings, Parser};
 
 use std::path::PathBuf;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
+mod attributes;
+mod batch;
+mod browse;
+mod facets;
+mod filters;
+mod fts_query;
 mod handlers;
+mod highlight;
 mod index_engine;
 mod index_manager;
+mod search_params;
+mod settings;
 mod stats;
+mod synonyms;
+mod tasks;
 
 #[macro_use]
 extern crate log;
@@ -48,10 +59,12 @@ async fn main() -> std::io::Result<()> {
     let http_port = cli.http_port.unwrap_or(3000);
     info!("Http port: {}", http_port);
 
+    let stats = Arc::new(Mutex::new(stats::SearchStats::new("main".to_string())));
     let data = web::Data::new(Mutex::new(index_manager::IndexManager::new(
         std::env::current_dir().unwrap(),
+        stats.clone(),
     )));
-    let stats = web::Data::new(Mutex::new(stats::SearchStats::new("main".to_string())));
+    let stats = web::Data::from(stats);
 
     HttpServer::new(move || {
         App::new()
@@ -60,9 +73,32 @@ async fn main() -> std::io::Result<()> {
             .app_data(stats.clone())
             .service(handlers::search_index)
             .service(handlers::index_document)
+            .service(handlers::search_stats)
             .service(handlers::index_stats)
+            .service(handlers::list_indexes)
+            .service(handlers::get_settings)
+            .service(handlers::get_task)
+            .service(handlers::get_synonym)
+            .service(handlers::get_object)
             .service(handlers::catch_get)
+            .service(handlers::set_settings)
+            .service(handlers::save_object)
+            .service(handlers::save_synonym)
+            .service(handlers::delete_synonym)
+            .service(handlers::delete_object)
+            .service(handlers::delete_index)
+            .service(handlers::clear_index)
+            .service(handlers::index_operation)
+            .service(handlers::partial_update_object)
+            .service(handlers::get_objects)
+            .service(handlers::multiple_queries)
             .service(handlers::query_index)
+            .service(handlers::search_facet_values)
+            .service(handlers::browse_index)
+            .service(handlers::delete_by_query)
+            .service(handlers::save_synonyms)
+            .service(handlers::clear_synonyms)
+            .service(handlers::search_synonyms)
             .service(handlers::batch_index)
     })
     .bind(("127.0.0.1", http_port))?
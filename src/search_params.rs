@@ -0,0 +1,497 @@
+// search parameters
+// Algolia clients send them as JSON keys and/or as a url encoded `params` string.
+// both are merged into one SearchParams, top level keys win
+use crate::attributes;
+use crate::facets::{DEFAULT_MAX_VALUES_PER_FACET, MAX_VALUES_PER_FACET};
+use crate::filters::{self, Filter};
+use crate::highlight::{
+    DEFAULT_ELLIPSIS, DEFAULT_POST_TAG, DEFAULT_PRE_TAG, DEFAULT_SNIPPET_WORDS, MAX_SNIPPET_WORDS,
+};
+use crate::index_engine::IndexError;
+use crate::settings::SEARCH_PARAMETER_SETTINGS;
+use json::JsonValue;
+
+pub const DEFAULT_HITS_PER_PAGE: u64 = 20;
+pub const MAX_HITS_PER_PAGE: u64 = 1000;
+pub const DEFAULT_MAX_FACET_HITS: u64 = 10;
+pub const MAX_FACET_HITS: u64 = 100;
+
+// keys of a search request that are not search parameters
+const REQUEST_KEYS: &[&str] = &["params", "indexName", "type", "facet", "cursor"];
+
+// Algolia search parameters morocco accepts but doesn't act upon yet.
+// anything neither here nor parsed below is reported as unknown
+pub const ACCEPTED_PARAMETERS: &[&str] = &[
+    "advancedSyntax",
+    "advancedSyntaxFeatures",
+    "allowTyposOnNumericTokens",
+    "alternativesAsExact",
+    "analytics",
+    "analyticsTags",
+    "aroundLatLng",
+    "aroundLatLngViaIP",
+    "aroundPrecision",
+    "aroundRadius",
+    "attributeCriteriaComputedByMinProximity",
+    "clickAnalytics",
+    "decompoundQuery",
+    "disableExactOnAttributes",
+    "disableTypoToleranceOnAttributes",
+    "distinct",
+    "enableABTest",
+    "enablePersonalization",
+    "enableReRanking",
+    "enableRules",
+    "exactOnSingleWordQuery",
+    "explain",
+    "facetingAfterDistinct",
+    "ignorePlurals",
+    "insideBoundingBox",
+    "insidePolygon",
+    "minimumAroundRadius",
+    "minProximity",
+    "minWordSizefor1Typo",
+    "minWordSizefor2Typos",
+    "naturalLanguages",
+    "optionalFilters",
+    "optionalWords",
+    "percentileComputation",
+    "personalizationImpact",
+    "queryLanguages",
+    "queryType",
+    "relevancyStrictness",
+    "removeStopWords",
+    "removeWordsIfNoResults",
+    "renderingContent",
+    "replaceSynonymsInHighlight",
+    "responseFields",
+    "restrictHighlightAndSnippetArrays",
+    "restrictSearchableAttributes",
+    "ruleContexts",
+    "similarQuery",
+    "sumOrFiltersScores",
+    "typoTolerance",
+    "userToken",
+];
+
+#[derive(Clone)]
+pub struct SearchParams {
+    pub query: String,
+    pub page: u64,
+    pub hits_per_page: u64,
+    // offset/length take precedence over page/hitsPerPage when set
+    pub offset: Option<u64>,
+    pub length: Option<u64>,
+    pub attributes_to_retrieve: Option<Vec<String>>,
+    // None highlights every attribute
+    pub attributes_to_highlight: Option<Vec<String>>,
+    pub highlight_pre_tag: String,
+    pub highlight_post_tag: String,
+    // attribute and number of words, from "attribute:words"
+    pub attributes_to_snippet: Vec<(String, usize)>,
+    pub snippet_ellipsis_text: String,
+    // adds _rankingInfo to every hit
+    pub get_ranking_info: bool,
+    // whether the index synonyms rewrite the query
+    pub synonyms: bool,
+    // attributes to count facet values of, "*" for all of them
+    pub facets: Vec<String>,
+    pub max_values_per_facet: u64,
+    // "count" or "alpha"
+    pub sort_facet_values_by: String,
+    // searching for facet values: the text to look for and how many values
+    pub facet_query: String,
+    pub max_facet_hits: u64,
+    // filters, facetFilters, numericFilters, tagFilters: the parameter,
+    // its text as given and the parsed filter. all of them must hold
+    filters: Vec<(String, String, Filter)>,
+    // keys given by the request, index settings don't override them
+    explicit: Vec<String>,
+}
+
+impl Default for SearchParams {
+    fn default() -> Self {
+        SearchParams {
+            query: String::new(),
+            page: 0,
+            hits_per_page: DEFAULT_HITS_PER_PAGE,
+            offset: None,
+            length: None,
+            attributes_to_retrieve: None,
+            attributes_to_highlight: None,
+            highlight_pre_tag: DEFAULT_PRE_TAG.to_string(),
+            highlight_post_tag: DEFAULT_POST_TAG.to_string(),
+            attributes_to_snippet: Vec::new(),
+            snippet_ellipsis_text: DEFAULT_ELLIPSIS.to_string(),
+            get_ranking_info: false,
+            synonyms: true,
+            facets: Vec::new(),
+            max_values_per_facet: DEFAULT_MAX_VALUES_PER_FACET,
+            sort_facet_values_by: "count".to_string(),
+            facet_query: String::new(),
+            max_facet_hits: DEFAULT_MAX_FACET_HITS,
+            filters: Vec::new(),
+            explicit: Vec::new(),
+        }
+    }
+}
+
+impl SearchParams {
+    // whether an attribute gets an entry in _highlightResult
+    pub fn highlights(&self, attribute: &str) -> bool {
+        match &self.attributes_to_highlight {
+            Some(names) => names
+                .iter()
+                .any(|a| a == "*" || attributes::covers(a, attribute)),
+            None => true,
+        }
+    }
+
+    // number of words for an attribute's snippet, None when it isn't snippeted
+    pub fn snippet_words(&self, attribute: &str) -> Option<usize> {
+        self.attributes_to_snippet
+            .iter()
+            .find(|(a, _)| a == "*" || attributes::covers(a, attribute))
+            .map(|(_, words)| *words)
+    }
+
+    // parses a search request body: {"query": "...", "params": "hitsPerPage=2&..."}
+    pub fn from_request(request: &JsonValue) -> Result<SearchParams, IndexError> {
+        let mut params = SearchParams::default();
+
+        if let Some(encoded) = request["params"].as_str() {
+            let pairs: Vec<(String, String)> = serde_urlencoded::from_str(encoded)
+                .map_err(|e| IndexError::InvalidParameter(format!("invalid params: {}", e)))?;
+            for (key, value) in pairs {
+                params.set(&key, &JsonValue::from(value))?;
+            }
+        } else if !request["params"].is_null() {
+            return Err(IndexError::InvalidParameter(
+                "params must be a url encoded string".to_string(),
+            ));
+        }
+
+        for (key, value) in request.entries() {
+            if !REQUEST_KEYS.contains(&key) {
+                params.set(key, value)?;
+            }
+        }
+        Ok(params)
+    }
+
+    // whether the request gave a parameter
+    pub fn is_set(&self, key: &str) -> bool {
+        self.explicit.iter().any(|k| k == key)
+    }
+
+    // the parameters with index settings filling in what the request left out
+    pub fn with_settings(&self, settings: &JsonValue) -> SearchParams {
+        let mut params = self.clone();
+        for key in SEARCH_PARAMETER_SETTINGS {
+            if self.is_set(key) || settings[*key].is_null() {
+                continue;
+            }
+            // settings are validated when stored
+            if let Err(e) = params.set(key, &settings[*key]) {
+                info!("ignoring setting {}: {}", key, e);
+            }
+        }
+        params
+    }
+
+    pub fn set(&mut self, key: &str, value: &JsonValue) -> Result<(), IndexError> {
+        self.explicit.push(key.to_string());
+        match key {
+            "query" => self.query = parse_string(value),
+            "page" => self.page = parse_u64(key, value)?,
+            "hitsPerPage" => self.hits_per_page = parse_u64(key, value)?.min(MAX_HITS_PER_PAGE),
+            "offset" => self.offset = Some(parse_u64(key, value)?),
+            "length" => self.length = Some(parse_u64(key, value)?.min(MAX_HITS_PER_PAGE)),
+            "attributesToRetrieve" => self.attributes_to_retrieve = Some(parse_list(key, value)?),
+            "attributesToHighlight" => self.attributes_to_highlight = Some(parse_list(key, value)?),
+            "highlightPreTag" => self.highlight_pre_tag = parse_string(value),
+            "highlightPostTag" => self.highlight_post_tag = parse_string(value),
+            "attributesToSnippet" => {
+                self.attributes_to_snippet = parse_list(key, value)?
+                    .iter()
+                    .map(|a| parse_snippet_attribute(a))
+                    .collect::<Result<Vec<(String, usize)>, IndexError>>()?
+            }
+            "snippetEllipsisText" => self.snippet_ellipsis_text = parse_string(value),
+            "getRankingInfo" => self.get_ranking_info = parse_bool(key, value)?,
+            "synonyms" => self.synonyms = parse_bool(key, value)?,
+            "facets" => self.facets = parse_list(key, value)?,
+            "maxValuesPerFacet" => {
+                self.max_values_per_facet = parse_u64(key, value)?.min(MAX_VALUES_PER_FACET)
+            }
+            "sortFacetValuesBy" => match value.as_str() {
+                Some(sort @ ("count" | "alpha")) => self.sort_facet_values_by = sort.to_string(),
+                _ => return Err(invalid_value(key, value)),
+            },
+            "facetQuery" => self.facet_query = parse_string(value),
+            "maxFacetHits" => self.max_facet_hits = parse_u64(key, value)?.min(MAX_FACET_HITS),
+            "filters" => {
+                let expression = parse_string(value);
+                let filter = match expression.trim() {
+                    "" => None,
+                    expression => Some(filters::parse(expression)?),
+                };
+                self.set_filter(key, expression, filter)
+            }
+            "facetFilters" | "numericFilters" | "tagFilters" => {
+                let text = value
+                    .as_str()
+                    .map(|v| v.to_string())
+                    .unwrap_or(value.dump());
+                self.set_filter(key, text, filters::from_legacy(key, value)?)
+            }
+            _ if ACCEPTED_PARAMETERS.contains(&key) => {
+                debug!("search parameter {} is not supported, ignoring it", key)
+            }
+            _ => {
+                return Err(IndexError::InvalidParameter(format!(
+                    "Unknown parameter: {}",
+                    key
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    fn set_filter(&mut self, key: &str, text: String, filter: Option<Filter>) {
+        self.filters.retain(|(k, _, _)| k != key);
+        if let Some(filter) = filter {
+            self.filters.push((key.to_string(), text, filter));
+        }
+    }
+
+    // every filter parameter combined, None when there is nothing to filter on
+    pub fn filter(&self) -> Option<Filter> {
+        let mut filters: Vec<Filter> = self.filters.iter().map(|(_, _, f)| f.clone()).collect();
+        match filters.len() {
+            0 => None,
+            1 => Some(filters.remove(0)),
+            _ => Some(Filter::And(filters)),
+        }
+    }
+
+    // rows to skip and rows to return. pages too far out to count skip
+    // every row and come back empty
+    pub fn offset_length(&self) -> (u64, u64) {
+        match self.offset {
+            Some(offset) => (offset, self.length.unwrap_or(self.hits_per_page)),
+            None => (
+                self.page.saturating_mul(self.hits_per_page),
+                self.hits_per_page,
+            ),
+        }
+    }
+
+    // the parameters as Algolia echoes them back in search responses
+    pub fn to_query_string(&self) -> String {
+        let mut params = vec![
+            ("query", self.query.clone()),
+            ("page", self.page.to_string()),
+            ("hitsPerPage", self.hits_per_page.to_string()),
+        ];
+        if let Some(offset) = self.offset {
+            params.push(("offset", offset.to_string()));
+        }
+        if let Some(length) = self.length {
+            params.push(("length", length.to_string()));
+        }
+        if let Some(attributes) = &self.attributes_to_retrieve {
+            params.push(("attributesToRetrieve", attributes.join(",")));
+        }
+        if let Some(attributes) = &self.attributes_to_highlight {
+            params.push(("attributesToHighlight", attributes.join(",")));
+        }
+        if !self.attributes_to_snippet.is_empty() {
+            let attributes: Vec<String> = self
+                .attributes_to_snippet
+                .iter()
+                .map(|(attribute, words)| format!("{}:{}", attribute, words))
+                .collect();
+            params.push(("attributesToSnippet", attributes.join(",")));
+        }
+        if !self.facets.is_empty() {
+            params.push(("facets", self.facets.join(",")));
+        }
+        for (key, text, _) in &self.filters {
+            params.push((key.as_str(), text.clone()));
+        }
+        if self.get_ranking_info {
+            params.push(("getRankingInfo", "true".to_string()));
+        }
+        if !self.synonyms {
+            params.push(("synonyms", "false".to_string()));
+        }
+        serde_urlencoded::to_string(params).unwrap_or_default()
+    }
+}
+
+// list parameters come either as a JSON array (algoliasearch) or comma separated
+pub fn parse_list_parameter(value: &str) -> Vec<String> {
+    match json::parse(value) {
+        Ok(list) if list.is_array() => list.members().map(|v| v.to_string()).collect(),
+        _ => value
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect(),
+    }
+}
+
+fn parse_list(key: &str, value: &JsonValue) -> Result<Vec<String>, IndexError> {
+    if value.is_array() {
+        Ok(value.members().map(|v| v.to_string()).collect())
+    } else if let Some(value) = value.as_str() {
+        Ok(parse_list_parameter(value))
+    } else {
+        Err(invalid_value(key, value))
+    }
+}
+
+// "body:20" snippets 20 words of body, "body" the default amount
+fn parse_snippet_attribute(value: &str) -> Result<(String, usize), IndexError> {
+    match value.rsplit_once(':') {
+        Some((attribute, words)) => match words.trim().parse::<usize>() {
+            Ok(words) => Ok((attribute.to_string(), words.clamp(1, MAX_SNIPPET_WORDS))),
+            Err(_) => Err(IndexError::InvalidParameter(format!(
+                "Invalid value for attributesToSnippet: {}",
+                value
+            ))),
+        },
+        None => Ok((value.to_string(), DEFAULT_SNIPPET_WORDS)),
+    }
+}
+
+fn parse_string(value: &JsonValue) -> String {
+    if value.is_null() {
+        String::new()
+    } else {
+        value.to_string()
+    }
+}
+
+fn parse_bool(key: &str, value: &JsonValue) -> Result<bool, IndexError> {
+    match value {
+        JsonValue::Boolean(b) => Some(*b),
+        _ => match value.as_str().map(|v| v.trim()) {
+            Some("true") | Some("1") => Some(true),
+            Some("false") | Some("0") => Some(false),
+            _ => None,
+        },
+    }
+    .ok_or_else(|| invalid_value(key, value))
+}
+
+fn parse_u64(key: &str, value: &JsonValue) -> Result<u64, IndexError> {
+    match value {
+        JsonValue::Number(_) => value.as_u64(),
+        _ => value.as_str().and_then(|v| v.trim().parse().ok()),
+    }
+    .ok_or_else(|| invalid_value(key, value))
+}
+
+fn invalid_value(key: &str, value: &JsonValue) -> IndexError {
+    IndexError::InvalidParameter(format!("Invalid value for {}: {}", key, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use json::object;
+
+    #[test]
+    fn params_string_and_keys_are_merged() {
+        let params = SearchParams::from_request(&object! {
+            params: "query=red%20shoes&hitsPerPage=5&attributesToRetrieve=name%2Cprice",
+            page: 2,
+            facets: ["brand"],
+        })
+        .unwrap();
+        assert_eq!(params.query, "red shoes");
+        assert_eq!(params.hits_per_page, 5);
+        assert_eq!(params.page, 2);
+        assert_eq!(
+            params.attributes_to_retrieve,
+            Some(vec!["name".to_string(), "price".to_string()])
+        );
+        assert_eq!(params.facets, vec!["brand".to_string()]);
+        assert!(params.is_set("hitsPerPage") && params.is_set("page"));
+        assert!(!params.is_set("maxValuesPerFacet"));
+    }
+
+    #[test]
+    fn top_level_keys_win_over_params() {
+        let params = SearchParams::from_request(&object! {
+            params: "query=from%20params&hitsPerPage=5&filters=price%20%3C%2010",
+            query: "from keys",
+            hitsPerPage: 7,
+            filters: "price > 20",
+        })
+        .unwrap();
+        assert_eq!(params.query, "from keys");
+        assert_eq!(params.hits_per_page, 7);
+        assert_eq!(params.filter(), Some(filters::parse("price > 20").unwrap()));
+    }
+
+    #[test]
+    fn filter_parameters_all_hold() {
+        let params = SearchParams::from_request(&object! {
+            params: "filters=price%20%3C%2010",
+            facetFilters: ["brand:acme"],
+        })
+        .unwrap();
+        assert_eq!(
+            params.filter(),
+            Some(Filter::And(vec![
+                filters::parse("price < 10").unwrap(),
+                filters::parse("brand:acme").unwrap(),
+            ]))
+        );
+    }
+
+    #[test]
+    fn unknown_parameters_are_refused() {
+        let status = |request: JsonValue| {
+            SearchParams::from_request(&request)
+                .err()
+                .map(|e| e.status())
+        };
+        assert_eq!(status(object! { bogus: 1 }), Some(400));
+        assert_eq!(status(object! { params: "bogus=1" }), Some(400));
+        assert_eq!(status(object! { params: 12 }), Some(400));
+        assert_eq!(status(object! { hitsPerPage: "many" }), Some(400));
+        assert_eq!(status(object! { params: "hitsPerPage=many" }), Some(400));
+        // accepted Algolia parameters and request keys are not parameters
+        assert_eq!(
+            status(object! { typoTolerance: false, indexName: "products" }),
+            None
+        );
+    }
+
+    #[test]
+    fn settings_only_fill_in_what_the_request_left_out() {
+        let params = SearchParams::from_request(&object! { params: "hitsPerPage=3" }).unwrap();
+        let params = params.with_settings(&object! { hitsPerPage: 50, maxValuesPerFacet: 7 });
+        assert_eq!(params.hits_per_page, 3);
+        assert_eq!(params.max_values_per_facet, 7);
+    }
+
+    #[test]
+    fn query_strings_round_trip() {
+        let params = SearchParams::from_request(&object! {
+            query: "a&b=c",
+            page: 1,
+            filters: "brand:\"acme inc\"",
+        })
+        .unwrap();
+        let parsed =
+            SearchParams::from_request(&object! { params: params.to_query_string() }).unwrap();
+        assert_eq!(parsed.query, "a&b=c");
+        assert_eq!(parsed.page, 1);
+        assert_eq!(parsed.filter(), params.filter());
+    }
+}
@@ -0,0 +1,383 @@
+// index settings
+// stored as the JSON object Algolia clients send to /settings, validated on
+// write. settings that are also search parameters are the query defaults
+use crate::attributes;
+use crate::index_engine::IndexError;
+use crate::search_params::{SearchParams, ACCEPTED_PARAMETERS};
+use json::object;
+use json::JsonValue;
+
+// settings that double as search parameters, parsed by SearchParams
+pub const SEARCH_PARAMETER_SETTINGS: &[&str] = &[
+    "hitsPerPage",
+    "attributesToRetrieve",
+    "attributesToHighlight",
+    "attributesToSnippet",
+    "highlightPreTag",
+    "highlightPostTag",
+    "snippetEllipsisText",
+    "maxValuesPerFacet",
+    "maxFacetHits",
+    "sortFacetValuesBy",
+];
+
+// Algolia index settings morocco stores and reports but doesn't act upon.
+// search parameters it accepts are accepted as settings too
+const ACCEPTED_SETTINGS: &[&str] = &[
+    "allowCompressionOfIntegerArray",
+    "attributeForDistinct",
+    "attributesToTransliterate",
+    "camelCaseAttributes",
+    "customNormalization",
+    "decompoundedAttributes",
+    "disablePrefixOnAttributes",
+    "disableTypoToleranceOnWords",
+    "indexLanguages",
+    "keepDiacriticsOnCharacters",
+    "mode",
+    "numericAttributesForFiltering",
+    "paginationLimitedTo",
+    "reRankingApplyFilter",
+    "replicas",
+    "semanticSearch",
+    "separatorsToIndex",
+    "userData",
+];
+
+// settings holding a list of attribute names
+const ATTRIBUTE_LIST_SETTINGS: &[&str] = &[
+    "searchableAttributes",
+    "attributesForFaceting",
+    "unretrievableAttributes",
+];
+
+const DEFAULT_RANKING: &[&str] = &[
+    "typo",
+    "geo",
+    "words",
+    "filters",
+    "proximity",
+    "attribute",
+    "exact",
+    "custom",
+];
+
+// Algolia's textual criteria, all of them are covered by bm25() here
+const TEXTUAL_CRITERIA: &[&str] = &[
+    "typo",
+    "geo",
+    "words",
+    "filters",
+    "proximity",
+    "attribute",
+    "exact",
+];
+
+pub struct FacetAttribute {
+    pub name: String,
+    // filterOnly(attribute): filterable, but no facet counts
+    pub filter_only: bool,
+    // searchable(attribute): its values can be searched for
+    pub searchable: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RankingCriterion {
+    // FTS5 relevance
+    Relevance,
+    Asc(String),
+    Desc(String),
+}
+
+// the settings as GET /settings reports them, defaults included
+pub fn with_defaults(settings: &JsonValue) -> JsonValue {
+    let mut out = object! {
+        searchableAttributes: null,
+        attributesForFaceting: null,
+        unretrievableAttributes: null,
+        attributesToRetrieve: null,
+        attributesToHighlight: null,
+        attributesToSnippet: null,
+        highlightPreTag: crate::highlight::DEFAULT_PRE_TAG,
+        highlightPostTag: crate::highlight::DEFAULT_POST_TAG,
+        snippetEllipsisText: crate::highlight::DEFAULT_ELLIPSIS,
+        ranking: DEFAULT_RANKING,
+        customRanking: null,
+        hitsPerPage: crate::search_params::DEFAULT_HITS_PER_PAGE,
+        maxValuesPerFacet: crate::facets::DEFAULT_MAX_VALUES_PER_FACET,
+        maxFacetHits: crate::search_params::DEFAULT_MAX_FACET_HITS,
+        sortFacetValuesBy: "count",
+    };
+    for (key, value) in settings.entries() {
+        out[key] = value.clone();
+    }
+    out
+}
+
+// applies a settings update: given keys replace the stored ones, null resets
+// a setting to its default. nothing is changed when any key is invalid
+pub fn merge(current: &JsonValue, update: &JsonValue) -> Result<JsonValue, IndexError> {
+    if !update.is_object() {
+        return Err(IndexError::InvalidParameter(
+            "settings must be a JSON object".to_string(),
+        ));
+    }
+
+    let mut merged = current.clone();
+    for (key, value) in update.entries() {
+        if value.is_null() {
+            merged.remove(key);
+            continue;
+        }
+        validate(key, value)?;
+        merged[key] = value.clone();
+    }
+    Ok(merged)
+}
+
+fn validate(key: &str, value: &JsonValue) -> Result<(), IndexError> {
+    if SEARCH_PARAMETER_SETTINGS.contains(&key) {
+        return SearchParams::default().set(key, value);
+    }
+    if ATTRIBUTE_LIST_SETTINGS.contains(&key) {
+        return string_list(key, value).map(|_| ());
+    }
+    match key {
+        "customRanking" => {
+            for criterion in string_list(key, value)? {
+                match parse_criterion(&criterion) {
+                    Some(RankingCriterion::Relevance) | None => {
+                        return Err(invalid_setting(key, &criterion))
+                    }
+                    Some(_) => (),
+                }
+            }
+            Ok(())
+        }
+        "ranking" => {
+            for criterion in string_list(key, value)? {
+                if criterion != "custom" && parse_criterion(&criterion).is_none() {
+                    return Err(invalid_setting(key, &criterion));
+                }
+            }
+            Ok(())
+        }
+        _ if ACCEPTED_SETTINGS.contains(&key) || ACCEPTED_PARAMETERS.contains(&key) => {
+            debug!("setting {} is not supported, storing it as given", key);
+            Ok(())
+        }
+        _ => Err(IndexError::InvalidParameter(format!(
+            "Unknown setting: {}",
+            key
+        ))),
+    }
+}
+
+// a list setting, empty when not set
+pub fn list(settings: &JsonValue, key: &str) -> Vec<String> {
+    settings[key].members().map(|v| v.to_string()).collect()
+}
+
+// the attribute paths of searchableAttributes, by priority.
+// "title,subtitle" share a priority, unordered() makes no difference here
+pub fn searchable_attributes(settings: &JsonValue) -> Vec<Vec<String>> {
+    list(settings, "searchableAttributes")
+        .iter()
+        .map(|entry| {
+            entry
+                .split(',')
+                .map(|name| {
+                    let name = name.trim();
+                    attributes::path(
+                        name.strip_prefix("unordered(")
+                            .and_then(|n| n.strip_suffix(')'))
+                            .unwrap_or(name),
+                    )
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// attributesForFaceting with its modifiers parsed, one entry per attribute.
+// an attribute listed twice, brand and searchable(brand), gets the
+// modifiers of both
+pub fn faceting_attributes(settings: &JsonValue) -> Vec<FacetAttribute> {
+    let mut attributes: Vec<FacetAttribute> = vec![];
+    for entry in list(settings, "attributesForFaceting") {
+        let attribute = facet_attribute(&entry);
+        match attributes.iter_mut().find(|a| a.name == attribute.name) {
+            Some(listed) => {
+                listed.filter_only &= attribute.filter_only;
+                listed.searchable |= attribute.searchable;
+            }
+            None => attributes.push(attribute),
+        }
+    }
+    attributes
+}
+
+fn facet_attribute(entry: &str) -> FacetAttribute {
+    let modifier = |prefix: &str| {
+        entry
+            .strip_prefix(prefix)
+            .and_then(|e| e.strip_suffix(')'))
+            .map(attributes::path)
+    };
+    if let Some(name) = modifier("filterOnly(") {
+        FacetAttribute {
+            name,
+            filter_only: true,
+            searchable: false,
+        }
+    } else if let Some(name) = modifier("searchable(") {
+        FacetAttribute {
+            name,
+            filter_only: false,
+            searchable: true,
+        }
+    } else {
+        FacetAttribute {
+            name: modifier("afterDistinct(").unwrap_or_else(|| attributes::path(entry)),
+            filter_only: false,
+            searchable: false,
+        }
+    }
+}
+
+// ranking with "custom" replaced by the customRanking criteria.
+// consecutive textual criteria collapse into a single relevance criterion
+pub fn ranking_criteria(settings: &JsonValue) -> Vec<RankingCriterion> {
+    let ranking = if settings["ranking"].is_array() {
+        list(settings, "ranking")
+    } else {
+        DEFAULT_RANKING.iter().map(|c| c.to_string()).collect()
+    };
+
+    let mut criteria = vec![];
+    for criterion in ranking {
+        if criterion == "custom" {
+            criteria.extend(
+                list(settings, "customRanking")
+                    .iter()
+                    .filter_map(|c| parse_criterion(c)),
+            );
+            continue;
+        }
+        match parse_criterion(&criterion) {
+            Some(RankingCriterion::Relevance)
+                if matches!(criteria.last(), Some(RankingCriterion::Relevance)) => {}
+            Some(criterion) => criteria.push(criterion),
+            None => (),
+        }
+    }
+    criteria
+}
+
+fn parse_criterion(criterion: &str) -> Option<RankingCriterion> {
+    if TEXTUAL_CRITERIA.contains(&criterion) {
+        return Some(RankingCriterion::Relevance);
+    }
+    let attribute = |prefix: &str| {
+        criterion
+            .strip_prefix(prefix)
+            .and_then(|c| c.strip_suffix(')'))
+            .filter(|attribute| !attribute.is_empty())
+            .map(attributes::path)
+    };
+    if let Some(attribute) = attribute("asc(") {
+        return Some(RankingCriterion::Asc(attribute));
+    }
+    attribute("desc(").map(RankingCriterion::Desc)
+}
+
+fn string_list(key: &str, value: &JsonValue) -> Result<Vec<String>, IndexError> {
+    if !value.is_array() || value.members().any(|v| !v.is_string()) {
+        return Err(invalid_setting(key, &value.dump()));
+    }
+    Ok(value.members().map(|v| v.to_string()).collect())
+}
+
+fn invalid_setting(key: &str, value: &str) -> IndexError {
+    IndexError::InvalidParameter(format!("Invalid value for {}: {}", key, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn updates_replace_the_given_keys() {
+        let current = object! { hitsPerPage: 10, searchableAttributes: ["name"] };
+        let merged = merge(
+            &current,
+            &object! { hitsPerPage: 30, customRanking: ["desc(sales)"] },
+        )
+        .unwrap();
+        assert_eq!(
+            merged,
+            object! { hitsPerPage: 30, searchableAttributes: ["name"], customRanking: ["desc(sales)"] }
+        );
+    }
+
+    #[test]
+    fn null_resets_a_setting() {
+        let current = object! { hitsPerPage: 10, searchableAttributes: ["name"] };
+        let merged = merge(&current, &object! { hitsPerPage: null }).unwrap();
+        assert_eq!(merged, object! { searchableAttributes: ["name"] });
+        assert_eq!(
+            with_defaults(&merged)["hitsPerPage"],
+            crate::search_params::DEFAULT_HITS_PER_PAGE
+        );
+        // resetting a setting that isn't set is no error
+        assert_eq!(
+            merge(&current, &object! { ranking: null }).unwrap(),
+            current
+        );
+    }
+
+    #[test]
+    fn an_invalid_key_changes_nothing() {
+        let current = object! { hitsPerPage: 10 };
+        for update in [
+            object! { hitsPerPage: 20, bogus: 1 },
+            object! { hitsPerPage: 20, searchableAttributes: "name" },
+            object! { hitsPerPage: 20, customRanking: ["typo"] },
+            object! { hitsPerPage: 20, ranking: ["fastest"] },
+            object! { hitsPerPage: "twenty" },
+        ] {
+            let error = merge(&current, &update).err().unwrap();
+            assert_eq!(error.status(), 400, "{}", update);
+        }
+        assert!(merge(&current, &json::array![]).is_err());
+        assert_eq!(current, object! { hitsPerPage: 10 });
+    }
+
+    #[test]
+    fn known_algolia_settings_are_stored() {
+        let update =
+            object! { typoTolerance: "min", replicas: ["products_asc"], paginationLimitedTo: 500 };
+        assert_eq!(merge(&JsonValue::new_object(), &update).unwrap(), update);
+    }
+
+    #[test]
+    fn consecutive_textual_criteria_collapse() {
+        use RankingCriterion::*;
+        assert_eq!(ranking_criteria(&JsonValue::new_object()), vec![Relevance]);
+        let settings = object! {
+            ranking: ["typo", "words", "desc(sales)", "proximity", "exact", "custom", "attribute"],
+            customRanking: ["asc(price)", "desc(rating)"],
+        };
+        assert_eq!(
+            ranking_criteria(&settings),
+            vec![
+                Relevance,
+                Desc("sales".to_string()),
+                Relevance,
+                Asc("price".to_string()),
+                Desc("rating".to_string()),
+                Relevance,
+            ]
+        );
+    }
+}
@@ -20,6 +20,7 @@ pub struct SearchStats {
     empty_query_result_per_index: Arc<Mutex<HashMap<String, u64>>>,
     index_usage_count: Arc<Mutex<HashMap<String, u64>>>,
     documents_count_per_index: Arc<Mutex<HashMap<String, u64>>>,
+    deleted_documents_per_index: Arc<Mutex<HashMap<String, u64>>>,
     http_4xx_errors: Arc<Mutex<HashMap<String, u64>>>,
     http_5xx_errors: Arc<Mutex<HashMap<String, u64>>>,
 }
@@ -32,6 +33,7 @@ impl SearchStats {
             empty_query_result_per_index: Arc::new(Mutex::new(HashMap::new())),
             index_usage_count: Arc::new(Mutex::new(HashMap::new())),
             documents_count_per_index: Arc::new(Mutex::new(HashMap::new())),
+            deleted_documents_per_index: Arc::new(Mutex::new(HashMap::new())),
             http_4xx_errors: Arc::new(Mutex::new(HashMap::new())),
             http_5xx_errors: Arc::new(Mutex::new(HashMap::new())),
         }
@@ -43,7 +45,7 @@ impl SearchStats {
             .unwrap()
             .entry(index)
             .and_modify(|count| *count += 1)
-            .or_insert(0);
+            .or_insert(1);
     }
 
     pub fn increment_index_usage_counter(&mut self, index: String) {
@@ -52,7 +54,7 @@ impl SearchStats {
             .unwrap()
             .entry(index)
             .and_modify(|count| *count += 1)
-            .or_insert(0);
+            .or_insert(1);
     }
 
     pub fn increment_empty_result_counter(&mut self, index: String) {
@@ -61,16 +63,30 @@ impl SearchStats {
             .unwrap()
             .entry(index)
             .and_modify(|count| *count += 1)
-            .or_insert(0);
+            .or_insert(1);
     }
 
-    pub fn increment_docs_per_index_counter(&mut self, index: String) {
+    // the documents of an index once its last task is applied
+    pub fn set_documents_count(&mut self, index: String, count: u64) {
         self.documents_count_per_index
+            .lock()
+            .unwrap()
+            .insert(index, count);
+    }
+
+    // for an index that was deleted or moved away
+    pub fn remove_documents_count(&mut self, index: &str) {
+        self.documents_count_per_index.lock().unwrap().remove(index);
+    }
+
+    // documents removed by deleteByQuery
+    pub fn add_deleted_documents(&mut self, index: String, count: u64) {
+        *self
+            .deleted_documents_per_index
             .lock()
             .unwrap()
             .entry(index)
-            .and_modify(|count| *count += 1)
-            .or_insert(0);
+            .or_insert(0) += count;
     }
 
     pub fn increment_http_4xx_errors_counter(&mut self, index: String) {
@@ -79,7 +95,7 @@ impl SearchStats {
             .unwrap()
             .entry(index)
             .and_modify(|count| *count += 1)
-            .or_insert(0);
+            .or_insert(1);
     }
 
     pub fn increment_http_5xx_errors_counter(&mut self, index: String) {
@@ -88,7 +104,7 @@ impl SearchStats {
             .unwrap()
             .entry(index)
             .and_modify(|count| *count += 1)
-            .or_insert(0);
+            .or_insert(1);
     }
 
     pub fn dump_json(&mut self) -> Result<String, String> {
@@ -98,6 +114,7 @@ impl SearchStats {
             empty_query_result_per_index: self.empty_query_result_per_index.lock().unwrap().clone(),
             index_usage_count:self.index_usage_count.lock().unwrap().clone(),
             documents_count_per_index:self.documents_count_per_index.lock().unwrap().clone(),
+            deleted_documents_per_index:self.deleted_documents_per_index.lock().unwrap().clone(),
             http_4xx_errors:self.http_4xx_errors.lock().unwrap().clone(),
             http_5xx_errors:self.http_5xx_errors.lock().unwrap().clone(),
         };
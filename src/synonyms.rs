@@ -0,0 +1,336 @@
+// synonyms
+// a synonym set rewrites words of the query before it is matched: a query
+// word, or run of words, some set applies to becomes an FTS5 OR group of
+// itself and what the set lets it match. sets are kept as their JSON in the
+// synonyms side table and in memory, see IndexEngine::matching
+use crate::fts_query;
+use crate::index_engine::IndexError;
+use json::object;
+use json::JsonValue;
+
+pub const TYPES: &[&str] = &[
+    "synonym",
+    "onewaysynonym",
+    "altcorrection1",
+    "altcorrection2",
+    "placeholder",
+];
+
+#[derive(Clone)]
+pub enum Rule {
+    // every synonym matches all the others
+    Regular(Vec<String>),
+    // the input matches the synonyms, not the other way around
+    OneWay {
+        input: String,
+        synonyms: Vec<String>,
+    },
+    // a word matching its corrections. Algolia counts them as one or two
+    // typos, morocco has no typo ranking and treats both the same
+    AltCorrection {
+        word: String,
+        corrections: Vec<String>,
+        typos: u8,
+    },
+    // records holding the placeholder, <street>, match any of the
+    // replacements. placeholders are indexed with PLACEHOLDER_MARK so they
+    // don't match the bare word, see mark_placeholders
+    Placeholder {
+        placeholder: String,
+        replacements: Vec<String>,
+    },
+}
+
+#[derive(Clone)]
+pub struct Synonym {
+    pub object_id: String,
+    pub rule: Rule,
+}
+
+impl Synonym {
+    // parses a synonym set as Algolia clients send it
+    pub fn from_json(synonym: &JsonValue) -> Result<Synonym, IndexError> {
+        let object_id = match &synonym["objectID"] {
+            v if (v.is_string() || v.is_number()) && !v.to_string().is_empty() => v.to_string(),
+            _ => return Err(invalid("objectID is required")),
+        };
+        let kind = synonym["type"]
+            .as_str()
+            .and_then(parse_type)
+            .ok_or_else(|| invalid(&format!("Invalid synonym type: {}", synonym["type"])))?;
+        let rule = match kind {
+            "synonym" => Rule::Regular(words(synonym, "synonyms")?),
+            "onewaysynonym" => Rule::OneWay {
+                input: word(synonym, "input")?,
+                synonyms: words(synonym, "synonyms")?,
+            },
+            "placeholder" => {
+                let placeholder = word(synonym, "placeholder")?;
+                if !placeholder.starts_with('<') || !placeholder.ends_with('>') {
+                    return Err(invalid(&format!(
+                        "Invalid placeholder {}, it must look like <name>",
+                        placeholder
+                    )));
+                }
+                Rule::Placeholder {
+                    placeholder,
+                    replacements: words(synonym, "replacements")?,
+                }
+            }
+            _ => Rule::AltCorrection {
+                word: word(synonym, "word")?,
+                corrections: words(synonym, "corrections")?,
+                typos: if kind == "altcorrection1" { 1 } else { 2 },
+            },
+        };
+        Ok(Synonym { object_id, rule })
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut out = object! {
+            objectID: self.object_id.clone(),
+            type: self.kind(),
+        };
+        match &self.rule {
+            Rule::Regular(synonyms) => out["synonyms"] = synonyms.clone().into(),
+            Rule::OneWay { input, synonyms } => {
+                out["input"] = input.clone().into();
+                out["synonyms"] = synonyms.clone().into();
+            }
+            Rule::AltCorrection {
+                word, corrections, ..
+            } => {
+                out["word"] = word.clone().into();
+                out["corrections"] = corrections.clone().into();
+            }
+            Rule::Placeholder {
+                placeholder,
+                replacements,
+            } => {
+                out["placeholder"] = placeholder.clone().into();
+                out["replacements"] = replacements.clone().into();
+            }
+        }
+        out
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match &self.rule {
+            Rule::Regular(_) => "synonym",
+            Rule::OneWay { .. } => "onewaysynonym",
+            Rule::AltCorrection { typos: 1, .. } => "altcorrection1",
+            Rule::AltCorrection { .. } => "altcorrection2",
+            Rule::Placeholder { .. } => "placeholder",
+        }
+    }
+
+    // whether the objectID or any text of the set contains the query,
+    // for searching synonyms
+    pub fn mentions(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        let texts: Vec<&String> = match &self.rule {
+            Rule::Regular(synonyms) => synonyms.iter().collect(),
+            Rule::OneWay { input, synonyms } => std::iter::once(input).chain(synonyms).collect(),
+            Rule::AltCorrection {
+                word, corrections, ..
+            } => std::iter::once(word).chain(corrections).collect(),
+            Rule::Placeholder {
+                placeholder,
+                replacements,
+            } => std::iter::once(placeholder).chain(replacements).collect(),
+        };
+        std::iter::once(&self.object_id)
+            .chain(texts)
+            .any(|text| text.to_lowercase().contains(&query))
+    }
+
+    // the lowercased query words each rewrite applies to, with the texts
+    // they can match instead
+    fn rewrites(&self) -> Vec<(Vec<String>, Vec<String>)> {
+        match &self.rule {
+            Rule::Regular(synonyms) => synonyms
+                .iter()
+                .map(|synonym| (lowered_terms(synonym), synonyms.clone()))
+                .collect(),
+            Rule::OneWay { input, synonyms } => {
+                vec![(lowered_terms(input), synonyms.clone())]
+            }
+            Rule::AltCorrection {
+                word, corrections, ..
+            } => vec![(lowered_terms(word), corrections.clone())],
+            Rule::Placeholder {
+                placeholder,
+                replacements,
+            } => replacements
+                .iter()
+                .map(|replacement| {
+                    (
+                        lowered_terms(replacement),
+                        vec![mark_placeholders(placeholder)],
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+// a private use character, which FTS5 keeps in tokens but nobody types
+pub const PLACEHOLDER_MARK: char = '\u{E000}';
+
+// the text as indexed: the mark goes right after the < of anything looking
+// like a placeholder, <street> is then the token "\u{E000}street" and
+// records with the word street don't match the placeholder
+pub fn mark_placeholders(text: &str) -> String {
+    let mut marked = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        marked.push_str(&rest[..=start]);
+        rest = &rest[start + 1..];
+        let placeholder = rest
+            .find('>')
+            .map(|end| &rest[..end])
+            .filter(|name| !name.is_empty() && !name.contains('<'));
+        if placeholder.is_some_and(|name| !name.starts_with(char::is_whitespace)) {
+            marked.push(PLACEHOLDER_MARK);
+        }
+    }
+    marked.push_str(rest);
+    marked
+}
+
+// the indexed text as the document has it, for highlights and snippets
+pub fn unmark_placeholders(text: &str) -> String {
+    text.replace(PLACEHOLDER_MARK, "")
+}
+
+// a synonym type as Algolia accepts it: oneWaySynonym, onewaysynonym...
+pub fn parse_type(kind: &str) -> Option<&'static str> {
+    let kind = kind.to_lowercase();
+    TYPES.iter().find(|t| **t == kind).copied()
+}
+
+// the query terms grouped by synonyms. every group is a run of terms, as
+// typed, followed by the texts synonyms let it match. at each position the
+// longest run some set applies to is taken
+pub fn expand(terms: &[String], synonyms: &[Synonym]) -> Vec<Vec<String>> {
+    let rewrites: Vec<(Vec<String>, Vec<String>)> =
+        synonyms.iter().flat_map(|s| s.rewrites()).collect();
+    let lowered: Vec<String> = terms.iter().map(|term| term.to_lowercase()).collect();
+
+    let mut groups = vec![];
+    let mut i = 0;
+    while i < terms.len() {
+        let applying: Vec<&(Vec<String>, Vec<String>)> = rewrites
+            .iter()
+            .filter(|(words, _)| lowered[i..].starts_with(words))
+            .collect();
+        let run = applying
+            .iter()
+            .map(|(words, _)| words.len())
+            .max()
+            .unwrap_or(1);
+
+        let mut group = vec![terms[i..i + run].join(" ")];
+        for (_, alternatives) in applying.iter().filter(|(words, _)| words.len() == run) {
+            for alternative in alternatives {
+                if !group
+                    .iter()
+                    .any(|g| g.to_lowercase() == alternative.to_lowercase())
+                {
+                    group.push(alternative.clone());
+                }
+            }
+        }
+        groups.push(group);
+        i += run;
+    }
+    groups
+}
+
+fn lowered_terms(text: &str) -> Vec<String> {
+    fts_query::terms(text)
+        .iter()
+        .map(|term| term.to_lowercase())
+        .collect()
+}
+
+// a text of a set, which must give the query something to match
+fn word(synonym: &JsonValue, key: &str) -> Result<String, IndexError> {
+    match synonym[key].as_str() {
+        Some(text) if !fts_query::terms(text).is_empty() => Ok(text.to_string()),
+        _ => Err(invalid(&format!(
+            "Invalid value for {}: {}",
+            key, synonym[key]
+        ))),
+    }
+}
+
+fn words(synonym: &JsonValue, key: &str) -> Result<Vec<String>, IndexError> {
+    let list = &synonym[key];
+    let texts: Vec<Option<&str>> = list.members().map(|text| text.as_str()).collect();
+    if !list.is_array()
+        || texts.is_empty()
+        || texts
+            .iter()
+            .any(|text| text.is_none_or(|text| fts_query::terms(text).is_empty()))
+    {
+        return Err(invalid(&format!("Invalid value for {}: {}", key, list)));
+    }
+    Ok(texts
+        .into_iter()
+        .flatten()
+        .map(|text| text.to_string())
+        .collect())
+}
+
+fn invalid(message: &str) -> IndexError {
+    IndexError::InvalidParameter(message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placeholder() -> Synonym {
+        Synonym::from_json(&object! {
+            objectID: "street",
+            type: "placeholder",
+            placeholder: "<street>",
+            replacements: ["main", "broadway"],
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn placeholders_get_a_token_of_their_own() {
+        assert_eq!(
+            mark_placeholders("4 <street> ave"),
+            "4 <\u{E000}street> ave"
+        );
+        assert_eq!(
+            mark_placeholders("this street is long"),
+            "this street is long"
+        );
+        assert_eq!(mark_placeholders("a < b and c > d"), "a < b and c > d");
+        assert_eq!(mark_placeholders("<> <<x>"), "<> <<\u{E000}x>");
+        assert_eq!(
+            unmark_placeholders(&mark_placeholders("<street>")),
+            "<street>"
+        );
+    }
+
+    #[test]
+    fn replacements_match_the_marked_placeholder() {
+        let terms = vec!["main".to_string()];
+        assert_eq!(
+            expand(&terms, &[placeholder()]),
+            vec![vec!["main".to_string(), "<\u{E000}street>".to_string()]]
+        );
+        // the placeholder itself is not a replacement
+        let terms = vec!["street".to_string()];
+        assert_eq!(
+            expand(&terms, &[placeholder()]),
+            vec![vec!["street".to_string()]]
+        );
+    }
+}
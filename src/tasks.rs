@@ -0,0 +1,129 @@
+// write tasks
+// write endpoints queue their request as a task in the index database and
+// answer with its taskID right away. a worker thread per index applies the
+// pending tasks in taskID order, see IndexEngine::run_next_task
+use crate::batch::BatchAction;
+use crate::index_engine::{IndexEngine, IndexError};
+use crate::search_params::SearchParams;
+use crate::stats::SearchStats;
+use crate::synonyms::Synonym;
+use json::object;
+use json::JsonValue;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub const PUBLISHED: &str = "published";
+pub const NOT_PUBLISHED: &str = "notPublished";
+
+pub enum Task {
+    Batch(Vec<BatchAction>),
+    // deletes the records matching a search when the task is applied
+    DeleteByQuery(Box<SearchParams>),
+    // a settings update, merged into the settings when it is applied
+    Settings(JsonValue),
+    // synonym sets to save, replacing every other set with `replace`
+    SaveSynonyms {
+        synonyms: Vec<Synonym>,
+        replace: bool,
+    },
+    DeleteSynonym(String),
+    ClearSynonyms,
+}
+
+impl Task {
+    // the kind and payload stored in the tasks table
+    pub fn to_json(&self) -> (&'static str, JsonValue) {
+        match self {
+            Task::Batch(actions) => (
+                "batch",
+                object! {
+                    requests: actions.iter().map(|a| a.to_json()).collect::<Vec<JsonValue>>(),
+                },
+            ),
+            Task::DeleteByQuery(params) => (
+                "deleteByQuery",
+                object! { params: params.to_query_string() },
+            ),
+            Task::Settings(update) => ("settings", update.clone()),
+            Task::SaveSynonyms { synonyms, replace } => (
+                "saveSynonyms",
+                object! {
+                    synonyms: synonyms.iter().map(|s| s.to_json()).collect::<Vec<JsonValue>>(),
+                    replaceExistingSynonyms: *replace,
+                },
+            ),
+            Task::DeleteSynonym(object_id) => {
+                ("deleteSynonym", object! { objectID: object_id.clone() })
+            }
+            Task::ClearSynonyms => ("clearSynonyms", JsonValue::new_object()),
+        }
+    }
+
+    pub fn from_json(kind: &str, payload: &JsonValue) -> Result<Task, IndexError> {
+        match kind {
+            "batch" => Ok(Task::Batch(BatchAction::from_requests(
+                &payload["requests"],
+            )?)),
+            "deleteByQuery" => Ok(Task::DeleteByQuery(Box::new(SearchParams::from_request(
+                payload,
+            )?))),
+            "settings" => Ok(Task::Settings(payload.clone())),
+            "saveSynonyms" => Ok(Task::SaveSynonyms {
+                synonyms: payload["synonyms"]
+                    .members()
+                    .map(Synonym::from_json)
+                    .collect::<Result<Vec<Synonym>, IndexError>>()?,
+                replace: payload["replaceExistingSynonyms"].as_bool() == Some(true),
+            }),
+            "deleteSynonym" => Ok(Task::DeleteSynonym(payload["objectID"].to_string())),
+            "clearSynonyms" => Ok(Task::ClearSynonyms),
+            _ => Err(IndexError::Storage(format!("unknown task kind {}", kind))),
+        }
+    }
+}
+
+// starts the worker of an index. every message on the returned channel wakes
+// it up to apply the pending tasks, it stops when the channel is dropped.
+// tasks left pending by a previous run are applied right away
+pub fn spawn_worker(
+    index_name: String,
+    index: Arc<Mutex<IndexEngine>>,
+    stats: Arc<Mutex<SearchStats>>,
+) -> Sender<()> {
+    let (sender, receiver) = mpsc::channel::<()>();
+    thread::spawn(move || {
+        while receiver.recv().is_ok() {
+            // the index is only locked for one task at a time, searches
+            // run in between
+            loop {
+                let (applied, deleted, documents) = {
+                    let mut index_engine = index.lock().unwrap();
+                    (
+                        index_engine.run_next_task(),
+                        index_engine.take_deleted_by_query(),
+                        index_engine.document_count(),
+                    )
+                };
+                let mut search_stats = stats.lock().unwrap();
+                if deleted > 0 {
+                    search_stats.add_deleted_documents(index_name.clone(), deleted);
+                }
+                search_stats.set_documents_count(index_name.clone(), documents);
+                drop(search_stats);
+                match applied {
+                    Ok(true) => continue,
+                    Ok(false) => break,
+                    Err(e) => {
+                        info!("{}: task queue error: {}", index_name, e);
+                        break;
+                    }
+                }
+            }
+        }
+        debug!("{}: task worker stopped", index_name);
+    });
+    // the receiver is alive until the worker stops
+    sender.send(()).unwrap();
+    sender
+}